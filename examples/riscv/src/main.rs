@@ -90,7 +90,7 @@ fn virtio_blk(header: &'static mut VirtIOHeader) {
 
 fn virtio_gpu(header: &'static mut VirtIOHeader) {
     let mut gpu = VirtIOGpu::new(header).expect("failed to create gpu driver");
-    let fb = gpu.setup_framebuffer().expect("failed to get fb");
+    let fb = gpu.setup_framebuffer().expect("failed to get fb").buf;
     for y in 0..768 {
         for x in 0..1024 {
             let idx = (y * 1024 + x) * 4;
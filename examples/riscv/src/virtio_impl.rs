@@ -11,9 +11,14 @@ lazy_static! {
 
 #[no_mangle]
 extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
-    let paddr = DMA_PADDR.fetch_add(0x1000 * pages, Ordering::SeqCst);
-    trace!("alloc DMA: paddr={:#x}, pages={}", paddr, pages);
-    paddr
+    // smaller regions may have been allocated before, keep the pages aligned
+    virtio_dma_alloc_aligned(0x1000 * pages, 0x1000)
+}
+
+#[no_mangle]
+extern "C" fn virtio_dma_alloc32(pages: usize) -> PhysAddr {
+    // all the RAM of the qemu virt machine is below 4GB
+    virtio_dma_alloc(pages)
 }
 
 #[no_mangle]
@@ -22,6 +27,27 @@ extern "C" fn virtio_dma_dealloc(paddr: PhysAddr, pages: usize) -> i32 {
     0
 }
 
+#[no_mangle]
+extern "C" fn virtio_dma_alloc_aligned(size: usize, align: usize) -> PhysAddr {
+    let mut paddr = DMA_PADDR.load(Ordering::SeqCst);
+    loop {
+        let start = (paddr + align - 1) & !(align - 1);
+        match DMA_PADDR.compare_exchange(paddr, start + size, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {
+                trace!("alloc DMA: paddr={:#x}, size={:#x}, align={:#x}", start, size, align);
+                return start;
+            }
+            Err(current) => paddr = current,
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn virtio_dma_dealloc_aligned(paddr: PhysAddr, size: usize, align: usize) -> i32 {
+    trace!("dealloc DMA: paddr={:#x}, size={:#x}, align={:#x}", paddr, size, align);
+    0
+}
+
 #[no_mangle]
 extern "C" fn virtio_phys_to_virt(paddr: PhysAddr) -> VirtAddr {
     paddr
@@ -32,5 +58,11 @@ extern "C" fn virtio_virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
     vaddr
 }
 
+#[no_mangle]
+extern "C" fn virtio_virt_to_iova(vaddr: VirtAddr) -> IoVirtAddr {
+    vaddr
+}
+
 type VirtAddr = usize;
 type PhysAddr = usize;
+type IoVirtAddr = usize;
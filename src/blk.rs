@@ -2,7 +2,6 @@ use super::*;
 use crate::header::VirtIOHeader;
 use crate::queue::VirtQueue;
 use bitflags::*;
-use core::hint::spin_loop;
 use log::*;
 use volatile::Volatile;
 
@@ -12,36 +11,39 @@ use volatile::Volatile;
 /// and serviced (probably out of order) by the device except where noted.
 pub struct VirtIOBlk<'a> {
     header: &'static mut VirtIOHeader,
-    queue: VirtQueue<'a>,
+    queue: VirtQueue<'a, QUEUE_SIZE>,
     capacity: usize,
+    features: BlkFeature,
 }
 
 impl VirtIOBlk<'_> {
     /// Create a new VirtIO-Blk driver.
     pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
+        let mut negotiated_features = BlkFeature::empty();
         header.begin_init(|features| {
             let features = BlkFeature::from_bits_truncate(features);
-            info!("device features: {:?}", features);
+            debug!("device features: {:?}", features);
             // negotiate these flags only
-            let supported_features = BlkFeature::empty();
-            (features & supported_features).bits()
+            let supported_features = BlkFeature::FLUSH | BlkFeature::BARRIER;
+            negotiated_features = features & supported_features;
+            negotiated_features.bits()
         });
 
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut BlkConfig) };
-        info!("config: {:?}", config);
-        info!(
-            "found a block device of size {}KB",
-            config.capacity.read() / 2
-        );
-
-        let queue = VirtQueue::new(header, 0, 16)?;
+        let config = header.config::<BlkConfig>();
+        debug!("config: {:?}", config);
+        // there is no configuration generation in the legacy interface
+        let capacity = config.read_capacity(|| 0);
+        info!("found a block device of size {}KB", capacity / 2);
+
+        let queue = VirtQueue::new(header, 0, QUEUE_SIZE as u16)?;
         header.finish_init();
 
         Ok(VirtIOBlk {
             header,
             queue,
-            capacity: config.capacity.read() as usize,
+            capacity: capacity as usize,
+            features: negotiated_features,
         })
     }
 
@@ -54,18 +56,14 @@ impl VirtIOBlk<'_> {
     pub fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
         assert_eq!(buf.len(), BLK_SIZE);
         let req = BlkReq {
-            type_: ReqType::In,
+            type_: ReqType::In as u32,
             reserved: 0,
             sector: block_id as u64,
         };
         let mut resp = BlkResp::default();
-        self.queue.add(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
-        self.header.notify(0);
-        while !self.queue.can_pop() {
-            spin_loop();
-        }
-        self.queue.pop_used()?;
-        match resp.status {
+        self.queue
+            .submit_and_wait(self.header, &[req.as_buf()], &[buf, resp.as_buf_mut()], usize::MAX)?;
+        match resp.status() {
             RespStatus::Ok => Ok(()),
             _ => Err(Error::IoError),
         }
@@ -85,7 +83,7 @@ impl VirtIOBlk<'_> {
     ///
     /// It will submit request to the virtio block device and return a token identifying
     /// the position of the first Descriptor in the chain. If there are not enough
-    /// Descriptors to allocate, then it returns [Error::BufferTooSmall].
+    /// Descriptors to allocate, then it returns [Error::QueueFull].
     ///
     /// After the request is ready, `resp` will be updated and the caller can get the
     /// status of the request(e.g. succeed or failed) through it. However, the caller
@@ -107,12 +105,14 @@ impl VirtIOBlk<'_> {
     ) -> Result<u16> {
         assert_eq!(buf.len(), BLK_SIZE);
         let req = BlkReq {
-            type_: ReqType::In,
+            type_: ReqType::In as u32,
             reserved: 0,
             sector: block_id as u64,
         };
         let token = self.queue.add(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
-        self.header.notify(0);
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
         Ok(token)
     }
 
@@ -120,18 +120,14 @@ impl VirtIOBlk<'_> {
     pub fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result {
         assert_eq!(buf.len(), BLK_SIZE);
         let req = BlkReq {
-            type_: ReqType::Out,
+            type_: ReqType::Out as u32,
             reserved: 0,
             sector: block_id as u64,
         };
         let mut resp = BlkResp::default();
-        self.queue.add(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
-        self.header.notify(0);
-        while !self.queue.can_pop() {
-            spin_loop();
-        }
-        self.queue.pop_used()?;
-        match resp.status {
+        self.queue
+            .submit_and_wait(self.header, &[req.as_buf(), buf], &[resp.as_buf_mut()], usize::MAX)?;
+        match resp.status() {
             RespStatus::Ok => Ok(()),
             _ => Err(Error::IoError),
         }
@@ -162,12 +158,14 @@ impl VirtIOBlk<'_> {
     ) -> Result<u16> {
         assert_eq!(buf.len(), BLK_SIZE);
         let req = BlkReq {
-            type_: ReqType::Out,
+            type_: ReqType::Out as u32,
             reserved: 0,
             sector: block_id as u64,
         };
         let token = self.queue.add(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
-        self.header.notify(0);
+        if self.queue.should_notify() {
+            self.header.notify(0);
+        }
         Ok(token)
     }
 
@@ -183,6 +181,81 @@ impl VirtIOBlk<'_> {
     pub fn virt_queue_size(&self) -> u16 {
         self.queue.size()
     }
+
+    /// Flush the write cache of the device, so that the completed writes are
+    /// durable.
+    ///
+    /// With the legacy [`BlkFeature::BARRIER`], the flush is also a barrier,
+    /// as older devices require for it to order the preceding writes.
+    /// Return [`Error::Unsupported`] if the device does not offer
+    /// [`BlkFeature::FLUSH`].
+    pub fn flush(&mut self) -> Result {
+        if !self.features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        let mut type_ = ReqType::Flush as u32;
+        if self.supports_barrier() {
+            type_ |= REQ_TYPE_BARRIER;
+        }
+        let req = BlkReq::raw(type_, 0, 0);
+        let mut resp = BlkResp::default();
+        self.queue
+            .submit_and_wait(self.header, &[req.as_buf()], &[resp.as_buf_mut()], usize::MAX)?;
+        match resp.status() {
+            RespStatus::Ok => Ok(()),
+            RespStatus::Unsupported => Err(Error::Unsupported),
+            _ => Err(Error::IoError),
+        }
+    }
+
+    /// Whether the legacy VIRTIO_BLK_F_BARRIER has been negotiated, so that
+    /// flushes are sent as barriers.
+    pub fn supports_barrier(&self) -> bool {
+        self.features.contains(BlkFeature::BARRIER)
+    }
+}
+
+/// A device made of fixed-size blocks, as expected by filesystem crates.
+pub trait BlockDevice {
+    /// Read a block into `buf`, whose length is the block size.
+    fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result;
+    /// Write a block from `buf`, whose length is the block size.
+    fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result;
+    /// The size of a block in bytes.
+    fn block_size(&self) -> usize;
+    /// The number of blocks of the device.
+    fn num_blocks(&self) -> usize;
+    /// Make the completed writes durable. The default does nothing, for
+    /// devices which write through.
+    fn flush(&mut self) -> Result {
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtIOBlk<'_> {
+    fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        VirtIOBlk::read_block(self, block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        VirtIOBlk::write_block(self, block_id, buf)
+    }
+
+    fn block_size(&self) -> usize {
+        BLK_SIZE
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.capacity
+    }
+
+    fn flush(&mut self) -> Result {
+        // without VIRTIO_BLK_F_FLUSH the device writes through
+        if !self.features.contains(BlkFeature::FLUSH) {
+            return Ok(());
+        }
+        VirtIOBlk::flush(self)
+    }
 }
 
 #[repr(C)]
@@ -190,8 +263,10 @@ impl VirtIOBlk<'_> {
 pub struct BlkConfig {
     /// Number of 512 Bytes sectors
     pub capacity: Volatile<u64>,
-    size_max: Volatile<u32>,
-    seg_max: Volatile<u32>,
+    /// Maximum size of any single segment.
+    pub size_max: Volatile<u32>,
+    /// Maximum number of segments in a request.
+    pub seg_max: Volatile<u32>,
     cylinders: Volatile<u16>,
     heads: Volatile<u8>,
     sectors: Volatile<u8>,
@@ -200,47 +275,304 @@ pub struct BlkConfig {
     alignment_offset: Volatile<u8>,
     min_io_size: Volatile<u16>,
     opt_io_size: Volatile<u32>,
-    // ... ignored
+    writeback: Volatile<u8>,
+    unused0: Volatile<u8>,
+    /// The number of request queues, only valid if VIRTIO_BLK_F_MQ has been
+    /// negotiated. Unlike `num_queues` of the PCI common configuration, it
+    /// only counts the request queues.
+    num_queues: Volatile<u16>,
+    max_discard_sectors: Volatile<u32>,
+    max_discard_seg: Volatile<u32>,
+    discard_sector_alignment: Volatile<u32>,
+    max_write_zeroes_sectors: Volatile<u32>,
+    max_write_zeroes_seg: Volatile<u32>,
+    write_zeroes_may_unmap: Volatile<u8>,
+    unused1: [Volatile<u8>; 3],
+    /// Maximum number of sectors of a secure erase request.
+    pub max_secure_erase_sectors: Volatile<u32>,
+    max_secure_erase_seg: Volatile<u32>,
+    /// Alignment of a secure erase request, in sectors.
+    pub secure_erase_sector_alignment: Volatile<u32>,
+    /// The size of a zone in sectors, only valid if VIRTIO_BLK_F_ZONED has
+    /// been negotiated, as the following fields.
+    pub zone_sectors: Volatile<u32>,
+    /// Maximum number of zones which can be open at the same time, 0 if
+    /// there is no limit.
+    pub max_open_zones: Volatile<u32>,
+    /// Maximum number of zones which can be active at the same time, 0 if
+    /// there is no limit.
+    pub max_active_zones: Volatile<u32>,
+    /// Maximum number of sectors of a zone append request.
+    pub max_append_sectors: Volatile<u32>,
+    /// The alignment of writes in a sequential zone, in bytes.
+    pub write_granularity: Volatile<u32>,
+    /// The zoned model of the device, see [`ZoneModel`].
+    pub model: Volatile<u8>,
+    unused2: [Volatile<u8>; 3],
+}
+
+unsafe impl ConfigStruct for BlkConfig {}
+
+impl BlkConfig {
+    /// Read the number of request queues, 1 unless `features` contains
+    /// [`BlkFeature::MQ`].
+    ///
+    /// It must be read after FEATURES_OK has been set. Return
+    /// [`Error::ProtocolError`] if the device reports no request queue.
+    pub fn num_request_queues(&self, features: BlkFeature) -> Result<u16> {
+        if !features.contains(BlkFeature::MQ) {
+            return Ok(1);
+        }
+        match self.num_queues.read() {
+            0 => Err(Error::ProtocolError),
+            num_queues => Ok(num_queues),
+        }
+    }
+
+    /// Read the zone characteristics, only valid if VIRTIO_BLK_F_ZONED has
+    /// been negotiated.
+    pub fn zone_info(&self) -> ZoneInfo {
+        ZoneInfo {
+            zone_sectors: self.zone_sectors.read(),
+            max_open_zones: self.max_open_zones.read(),
+            max_active_zones: self.max_active_zones.read(),
+            max_append_sectors: self.max_append_sectors.read(),
+            write_granularity: self.write_granularity.read(),
+            model: ZoneModel::from(self.model.read()),
+        }
+    }
+
+    /// Read the capacity in 512-byte sectors.
+    ///
+    /// The capacity is read as two 32-bit halves, which a resize of the device
+    /// may tear, so it is read again until `gen_check` returns the same
+    /// configuration generation before and after.
+    pub fn read_capacity(&self, gen_check: impl Fn() -> u8) -> u64 {
+        let halves = &self.capacity as *const Volatile<u64> as *const u32;
+        loop {
+            let generation = gen_check();
+            // Safety: the configuration space is little-endian, the low half
+            // comes first.
+            let (low, high) = unsafe { (halves.read_volatile(), halves.add(1).read_volatile()) };
+            if gen_check() == generation {
+                return (high as u64) << 32 | low as u64;
+            }
+        }
+    }
+}
+
+/// The zone characteristics of a zoned block device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct ZoneInfo {
+    /// The size of a zone in sectors.
+    pub zone_sectors: u32,
+    /// Maximum number of open zones, 0 if there is no limit.
+    pub max_open_zones: u32,
+    /// Maximum number of active zones, 0 if there is no limit.
+    pub max_active_zones: u32,
+    /// Maximum number of sectors of a zone append request.
+    pub max_append_sectors: u32,
+    /// The alignment of writes in a sequential zone, in bytes.
+    pub write_granularity: u32,
+    /// The zoned model of the device.
+    pub model: ZoneModel,
+}
+
+/// The zoned model of a block device.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ZoneModel {
+    /// The device is not zoned.
+    None = 0,
+    /// Host-managed: the zones must be written sequentially.
+    HostManaged = 1,
+    /// Host-aware: the zones should be written sequentially.
+    HostAware = 2,
+}
+
+impl From<u8> for ZoneModel {
+    fn from(model: u8) -> Self {
+        match model {
+            1 => ZoneModel::HostManaged,
+            2 => ZoneModel::HostAware,
+            _ => ZoneModel::None,
+        }
+    }
+}
+
+/// The header of a zone report, followed by the zone descriptors.
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct BlkZoneReport {
+    /// The number of zones the device has reported.
+    pub nr_zones: u64,
+    reserved: [u8; 56],
+}
+
+impl Default for BlkZoneReport {
+    fn default() -> Self {
+        BlkZoneReport {
+            nr_zones: 0,
+            reserved: [0; 56],
+        }
+    }
 }
 
+/// A zone, as described by a zone report.
+///
+/// Ref: VirtIO spec v1.2 section 5.2.6.1
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BlkZoneDescriptor {
+    /// The number of sectors which can be written in the zone.
+    pub capacity: u64,
+    /// The first sector of the zone.
+    pub start: u64,
+    /// The sector of the write pointer of the zone.
+    pub write_pointer: u64,
+    /// The type of the zone: 1 for conventional, 2 for sequential write
+    /// required, 3 for sequential write preferred.
+    pub zone_type: u8,
+    /// The state of the zone: 0 for no write pointer, 1 for empty, 2 for
+    /// implicitly open, 3 for explicitly open, 4 for closed, 13 for read-only,
+    /// 14 for full, 15 for offline.
+    pub state: u8,
+    reserved: [u8; 38],
+}
+
+impl Default for BlkZoneDescriptor {
+    fn default() -> Self {
+        BlkZoneDescriptor {
+            capacity: 0,
+            start: 0,
+            write_pointer: 0,
+            zone_type: 0,
+            state: 0,
+            reserved: [0; 38],
+        }
+    }
+}
+
+const _: [(); 64] = [(); size_of::<BlkZoneReport>()];
+const _: [(); 64] = [(); size_of::<BlkZoneDescriptor>()];
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct BlkReq {
-    type_: ReqType,
+    type_: u32,
     reserved: u32,
     sector: u64,
 }
 
 impl BlkReq {
     pub fn new(type_: ReqType, reserved: u32, sector: u64) -> Self {
+        Self::raw(type_ as u32, reserved, sector)
+    }
+
+    /// Create a request of `nblocks` blocks from `start_sector`, return it
+    /// with the length of its data.
+    ///
+    /// Return [`Error::InvalidParam`] if the range is empty, overflows or
+    /// goes beyond `capacity`, in sectors.
+    pub fn for_range(
+        type_: ReqType,
+        start_sector: u64,
+        nblocks: usize,
+        capacity: u64,
+    ) -> Result<(Self, usize)> {
+        let len = nblocks.checked_mul(BLK_SIZE).ok_or(Error::InvalidParam)?;
+        match start_sector.checked_add(nblocks as u64) {
+            Some(end) if nblocks != 0 && end <= capacity => {
+                Ok((Self::new(type_, 0, start_sector), len))
+            }
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Create a request of a type not modeled by [`ReqType`].
+    pub fn raw(type_: u32, reserved: u32, sector: u64) -> Self {
         Self { type_, reserved, sector }
     }
 }
 
+/// The data of a discard, write zeroes or secure erase request.
+#[repr(C)]
+#[derive(Debug)]
+pub struct BlkDiscardWriteZeroes {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl BlkDiscardWriteZeroes {
+    pub fn new(sector: u64, num_sectors: u32, flags: u32) -> Self {
+        Self {
+            sector,
+            num_sectors,
+            flags,
+        }
+    }
+}
+
 /// Response of a VirtIOBlk request.
 #[repr(C)]
 #[derive(Debug)]
 pub struct BlkResp {
-    status: RespStatus,
+    /// The raw status byte, as the device may write values which are not a
+    /// [`RespStatus`].
+    status: u8,
 }
 
 impl BlkResp {
     /// Return the status of a VirtIOBlk request.
     pub fn status(&self) -> RespStatus {
-        self.status
+        RespStatus::from(self.status)
     }
 }
 
+/// Type of a VirtIOBlk request.
 #[repr(u32)]
 #[derive(Debug)]
 pub enum ReqType {
+    /// Read.
     In = 0,
+    /// Write.
     Out = 1,
+    /// Flush the write cache.
     Flush = 4,
+    /// Discard sectors.
     Discard = 11,
+    /// Write zeroes to sectors.
     WriteZeroes = 13,
+    /// Securely erase sectors.
+    SecureErase = 14,
+    /// Append data to a zone, at its write pointer.
+    ZoneAppend = 15,
+    /// Report the zones.
+    ZoneReport = 16,
+    /// Open a zone explicitly.
+    ZoneOpen = 18,
+    /// Close a zone.
+    ZoneClose = 20,
+    /// Transition a zone to the full state.
+    ZoneFinish = 22,
+    /// Reset the write pointer of a zone.
+    ZoneReset = 24,
 }
 
+/// The bit of the request type which makes it a barrier, when the legacy
+/// VIRTIO_BLK_F_BARRIER has been negotiated.
+pub(crate) const REQ_TYPE_BARRIER: u32 = 1 << 31;
+
+/// The status of a zoned request the state of the zone does not allow.
+pub(crate) const RESP_ZONE_INVALID_CMD: u8 = 3;
+/// The status of a write to a sequential zone not at its write pointer.
+pub(crate) const RESP_ZONE_UNALIGNED_WP: u8 = 4;
+/// The status of a request which would exceed the maximum of open zones.
+pub(crate) const RESP_ZONE_OPEN_RESOURCE: u8 = 5;
+/// The status of a request which would exceed the maximum of active zones.
+pub(crate) const RESP_ZONE_ACTIVE_RESOURCE: u8 = 6;
+
 /// Status of a VirtIOBlk request.
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -251,21 +583,188 @@ pub enum RespStatus {
     IoErr = 1,
     /// Unsupported yet.
     Unsupported = 2,
-    /// Not ready.
-    _NotReady = 3,
+    /// Not ready, the initial value of the status until the device writes
+    /// it, which is none of the statuses of the spec, including those of
+    /// the zoned requests.
+    _NotReady = 0xff,
+}
+
+impl From<u8> for RespStatus {
+    fn from(status: u8) -> Self {
+        match status {
+            0 => RespStatus::Ok,
+            2 => RespStatus::Unsupported,
+            0xff => RespStatus::_NotReady,
+            _ => RespStatus::IoErr,
+        }
+    }
+}
+
+/// The data buffer of a raw request.
+#[derive(Debug)]
+pub enum RequestData<'a> {
+    /// A buffer written by the device.
+    In(&'a mut [u8]),
+    /// A buffer read by the device.
+    Out(&'a [u8]),
+    /// No data.
+    None,
 }
 
 impl Default for BlkResp {
     fn default() -> Self {
         BlkResp {
-            status: RespStatus::_NotReady,
+            status: RespStatus::_NotReady as u8,
+        }
+    }
+}
+
+/// The value of the guard bytes of a [`GuardedResp`].
+#[cfg(feature = "dma-guard")]
+const GUARD_BYTE: u8 = 0xa5;
+
+/// The number of guard bytes on each side of a [`GuardedResp`].
+#[cfg(feature = "dma-guard")]
+const GUARD_LEN: usize = 8;
+
+/// A response surrounded by guard bytes, to catch a device writing out of
+/// the bounds of the response, e.g. because a descriptor is mis-sized.
+///
+/// Only the response is given to the device, the guard bytes are checked by
+/// [`GuardedResp::check`] once the request has completed.
+#[cfg(feature = "dma-guard")]
+#[repr(C)]
+#[derive(Debug)]
+pub(crate) struct GuardedResp {
+    before: [u8; GUARD_LEN],
+    resp: BlkResp,
+    after: [u8; GUARD_LEN],
+}
+
+#[cfg(feature = "dma-guard")]
+impl GuardedResp {
+    /// Return the buffer of the response, ready to be added to the queue.
+    pub fn as_buf_mut(&mut self) -> &mut [u8] {
+        self.resp.as_buf_mut()
+    }
+
+    /// Return the status of the request.
+    pub fn status(&self) -> RespStatus {
+        self.resp.status()
+    }
+
+    /// Return [`Error::DeviceOverran`] if the device has written a guard
+    /// byte.
+    pub fn check(&self) -> Result {
+        let intact = |guard: &[u8; GUARD_LEN]| guard.iter().all(|&byte| byte == GUARD_BYTE);
+        if intact(&self.before) && intact(&self.after) {
+            Ok(())
+        } else {
+            warn!("device wrote out of the response: {:x?}", self);
+            Err(Error::DeviceOverran)
+        }
+    }
+}
+
+#[cfg(feature = "dma-guard")]
+impl Default for GuardedResp {
+    fn default() -> Self {
+        GuardedResp {
+            before: [GUARD_BYTE; GUARD_LEN],
+            resp: BlkResp::default(),
+            after: [GUARD_BYTE; GUARD_LEN],
+        }
+    }
+}
+
+/// A request with its response, as stored in [`BlkReqResp`].
+#[repr(C)]
+#[derive(Debug)]
+struct BlkReqRespPair {
+    req: BlkReq,
+    resp: BlkResp,
+}
+
+/// The number of pairs in a [`BlkReqResp`] pool.
+pub(crate) const REQ_RESP_POOL_SIZE: usize = 64;
+
+/// A pool of reusable request/response pairs in DMA memory.
+///
+/// It is useful when many requests are in flight at the same time, where
+/// allocating them on the stack is not viable.
+pub struct BlkReqResp {
+    /// DMA area of the pairs.
+    dma: DMA,
+    /// Bitmap of the pairs in use.
+    used: u64,
+}
+
+impl BlkReqResp {
+    /// Create a new pool.
+    pub fn new() -> Result<Self> {
+        let size = size_of::<BlkReqRespPair>() * REQ_RESP_POOL_SIZE;
+        Ok(BlkReqResp {
+            dma: DMA::new(pages(size))?,
+            used: 0,
+        })
+    }
+
+    /// Acquire a free pair for a request, return its index.
+    ///
+    /// The pair is zeroed before the request is written into it, and the
+    /// response status is reset. If all pairs are in use, return
+    /// [Error::QueueFull].
+    pub fn acquire(&mut self, type_: ReqType, sector: u64) -> Result<usize> {
+        let idx = (!self.used).trailing_zeros() as usize;
+        if idx >= REQ_RESP_POOL_SIZE {
+            return Err(Error::QueueFull);
         }
+        self.used |= 1 << idx;
+        let pair = self.pair_mut(idx);
+        pair.as_buf_mut().fill(0);
+        pair.req = BlkReq::new(type_, 0, sector);
+        pair.resp = BlkResp::default();
+        Ok(idx)
+    }
+
+    /// Release a pair so that it can be acquired again.
+    pub fn release(&mut self, idx: usize) {
+        assert!(self.in_use(idx), "release a free BlkReqResp pair");
+        self.used &= !(1 << idx);
+    }
+
+    /// Return the buffers of the request and of the response of a pair,
+    /// ready to be added to the queue.
+    pub fn buffers(&mut self, idx: usize) -> (&[u8], &mut [u8]) {
+        assert!(self.in_use(idx), "access a free BlkReqResp pair");
+        let pair = self.pair_mut(idx);
+        (pair.req.as_buf(), pair.resp.as_buf_mut())
+    }
+
+    /// Return the response of a pair.
+    pub fn resp(&self, idx: usize) -> &BlkResp {
+        assert!(self.in_use(idx), "access a free BlkReqResp pair");
+        let pairs = self.dma.vaddr() as *const BlkReqRespPair;
+        unsafe { &(*pairs.add(idx)).resp }
+    }
+
+    fn in_use(&self, idx: usize) -> bool {
+        idx < REQ_RESP_POOL_SIZE && self.used & (1 << idx) != 0
+    }
+
+    fn pair_mut(&mut self, idx: usize) -> &mut BlkReqRespPair {
+        let pairs = self.dma.vaddr() as *mut BlkReqRespPair;
+        unsafe { &mut *pairs.add(idx) }
     }
 }
 
 pub const BLK_SIZE: usize = 512;
 
+/// The size of the request queue.
+const QUEUE_SIZE: usize = 16;
+
 bitflags! {
+    /// Features of a VirtIOBlk device.
     pub struct BlkFeature: u64 {
         /// Device supports request barriers. (legacy)
         const BARRIER       = 1 << 0;
@@ -287,6 +786,9 @@ bitflags! {
         const TOPOLOGY      = 1 << 10;
         /// Device can toggle its cache between writeback and writethrough modes.
         const CONFIG_WCE    = 1 << 11;
+        /// Device supports multiqueue, the number of request queues is in
+        /// `num_queues`.
+        const MQ            = 1 << 12;
         /// Device can support discard command, maximum discard sectors size in
         /// `max_discard_sectors` and maximum discard segment number in
         /// `max_discard_seg`.
@@ -295,24 +797,100 @@ bitflags! {
         /// size in `max_write_zeroes_sectors` and maximum write zeroes segment
         /// number in `max_write_zeroes_seg`.
         const WRITE_ZEROES  = 1 << 14;
+        /// Device supports secure erase command, maximum erase sectors
+        /// count in `max_secure_erase_sectors` and maximum erase segment
+        /// number in `max_secure_erase_seg`.
+        const SECURE_ERASE  = 1 << 16;
+        /// Device is a zoned block device, its zone characteristics are in
+        /// the `zone_*` fields.
+        const ZONED         = 1 << 17;
 
         // device independent
+        /// Notify when the available ring is empty. (legacy)
         const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        /// Any descriptor layout is accepted. (legacy)
         const ANY_LAYOUT            = 1 << 27; // legacy
+        /// Indirect descriptors are supported.
         const RING_INDIRECT_DESC    = 1 << 28;
+        /// The used_event and avail_event fields are used.
         const RING_EVENT_IDX        = 1 << 29;
+        /// Unused. (legacy)
         const UNUSED                = 1 << 30; // legacy
+        /// The device complies with virtio 1.0 or later.
         const VERSION_1             = 1 << 32; // detect legacy
 
         // the following since virtio v1.1
+        /// The device is behind an IOMMU.
         const ACCESS_PLATFORM       = 1 << 33;
+        /// The packed virtqueue layout is supported.
         const RING_PACKED           = 1 << 34;
+        /// Buffers are used in the order they are made available.
         const IN_ORDER              = 1 << 35;
+        /// Memory accesses are ordered by the platform.
         const ORDER_PLATFORM        = 1 << 36;
+        /// Single root I/O virtualization is supported.
         const SR_IOV                = 1 << 37;
+        /// The driver passes extra data in its notifications.
         const NOTIFICATION_DATA     = 1 << 38;
+
+        // since virtio v1.2
+        /// A single queue can be reset.
+        const RING_RESET            = 1 << 40;
     }
 }
 
 unsafe impl AsBuf for BlkReq {}
 unsafe impl AsBuf for BlkResp {}
+unsafe impl AsBuf for BlkDiscardWriteZeroes {}
+unsafe impl AsBuf for BlkZoneReport {}
+unsafe impl AsBuf for BlkReqRespPair {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn num_request_queues_depends_on_mq() {
+        // Safety: the configuration is plain data, all zeroes is valid.
+        let mut config: BlkConfig = unsafe { core::mem::zeroed() };
+        config.num_queues.write(4);
+        assert_eq!(config.num_request_queues(BlkFeature::empty()), Ok(1));
+        assert_eq!(config.num_request_queues(BlkFeature::MQ), Ok(4));
+        // without MQ the field is not valid and is not read
+        config.num_queues.write(0);
+        assert_eq!(config.num_request_queues(BlkFeature::FLUSH), Ok(1));
+        assert_eq!(config.num_request_queues(BlkFeature::MQ), Err(Error::ProtocolError));
+    }
+
+    #[test]
+    fn config_layout() {
+        assert_eq!(offset_of!(BlkConfig, capacity), 0);
+        assert_eq!(offset_of!(BlkConfig, size_max), 8);
+        assert_eq!(offset_of!(BlkConfig, seg_max), 12);
+        assert_eq!(offset_of!(BlkConfig, cylinders), 16);
+        assert_eq!(offset_of!(BlkConfig, heads), 18);
+        assert_eq!(offset_of!(BlkConfig, sectors), 19);
+        assert_eq!(offset_of!(BlkConfig, blk_size), 20);
+        assert_eq!(offset_of!(BlkConfig, physical_block_exp), 24);
+        assert_eq!(offset_of!(BlkConfig, alignment_offset), 25);
+        assert_eq!(offset_of!(BlkConfig, min_io_size), 26);
+        assert_eq!(offset_of!(BlkConfig, opt_io_size), 28);
+        assert_eq!(offset_of!(BlkConfig, writeback), 32);
+        assert_eq!(offset_of!(BlkConfig, num_queues), 34);
+        assert_eq!(offset_of!(BlkConfig, max_discard_sectors), 36);
+        assert_eq!(offset_of!(BlkConfig, max_discard_seg), 40);
+        assert_eq!(offset_of!(BlkConfig, discard_sector_alignment), 44);
+        assert_eq!(offset_of!(BlkConfig, max_write_zeroes_sectors), 48);
+        assert_eq!(offset_of!(BlkConfig, max_write_zeroes_seg), 52);
+        assert_eq!(offset_of!(BlkConfig, write_zeroes_may_unmap), 56);
+        assert_eq!(offset_of!(BlkConfig, max_secure_erase_sectors), 60);
+        assert_eq!(offset_of!(BlkConfig, max_secure_erase_seg), 64);
+        assert_eq!(offset_of!(BlkConfig, secure_erase_sector_alignment), 68);
+        assert_eq!(offset_of!(BlkConfig, zone_sectors), 72);
+        assert_eq!(offset_of!(BlkConfig, max_open_zones), 76);
+        assert_eq!(offset_of!(BlkConfig, max_active_zones), 80);
+        assert_eq!(offset_of!(BlkConfig, max_append_sectors), 84);
+        assert_eq!(offset_of!(BlkConfig, write_granularity), 88);
+        assert_eq!(offset_of!(BlkConfig, model), 92);
+    }
+}
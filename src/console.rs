@@ -1,5 +1,6 @@
 use super::*;
 use crate::queue::VirtQueue;
+use alloc::vec::Vec;
 use bitflags::*;
 use core::{fmt, hint::spin_loop};
 use log::*;
@@ -7,32 +8,109 @@ use volatile::{ReadOnly, WriteOnly};
 
 const QUEUE_RECEIVEQ_PORT_0: usize = 0;
 const QUEUE_TRANSMITQ_PORT_0: usize = 1;
+const QUEUE_CONTROL_RECEIVEQ: usize = 2;
+const QUEUE_CONTROL_TRANSMITQ: usize = 3;
 
-/// Virtio console. Only one single port is allowed since ``alloc'' is disabled.
+/// The size of each queue.
+const QUEUE_SIZE: usize = 2;
+
+/// The maximum number of ports set up with VIRTIO_CONSOLE_F_MULTIPORT,
+/// including the port 0, since each port takes two queues.
+const MAX_PORTS: u32 = 8;
+
+/// Virtio console.
+///
+/// The port 0 is used by [`VirtIOConsole::send`] and [`VirtIOConsole::recv`].
+/// If the device supports VIRTIO_CONSOLE_F_MULTIPORT, the other ports are
+/// used through [`VirtIOConsole::open_port`].
 /// Emergency and cols/rows unimplemented.
 pub struct VirtIOConsole<'a> {
     header: &'static mut VirtIOHeader,
-    receiveq: VirtQueue<'a>,
-    transmitq: VirtQueue<'a>,
+    receiveq: VirtQueue<'a, QUEUE_SIZE>,
+    transmitq: VirtQueue<'a, QUEUE_SIZE>,
     queue_buf_dma: DMA,
     queue_buf_rx: &'a mut [u8],
     cursor: usize,
     pending_len: usize,
+    /// The control queues, if VIRTIO_CONSOLE_F_MULTIPORT is negotiated.
+    control: Option<Control<'a>>,
+    /// The ports other than the port 0, indexed by port id - 1.
+    ports: Vec<Port<'a>>,
+}
+
+/// The control queues of a multiport console.
+struct Control<'a> {
+    receiveq: VirtQueue<'a, QUEUE_SIZE>,
+    transmitq: VirtQueue<'a, QUEUE_SIZE>,
+    buf_dma: DMA,
+}
+
+/// A port other than the port 0 of a multiport console.
+struct Port<'a> {
+    receiveq: VirtQueue<'a, QUEUE_SIZE>,
+    transmitq: VirtQueue<'a, QUEUE_SIZE>,
+    /// Whether the device has announced the port with VIRTIO_CONSOLE_DEVICE_ADD.
+    added: bool,
+    /// Whether the host has connected to the port.
+    host_connected: bool,
+    /// The receive buffer, allocated when the port is opened.
+    buf_dma: Option<DMA>,
+    cursor: usize,
+    pending_len: usize,
+}
+
+/// The queue indices of a port.
+fn port_queues(port_id: u32) -> (usize, usize) {
+    match port_id {
+        0 => (QUEUE_RECEIVEQ_PORT_0, QUEUE_TRANSMITQ_PORT_0),
+        // the control queues come between the port 0 and the port 1
+        _ => (2 + 2 * port_id as usize, 3 + 2 * port_id as usize),
+    }
 }
 
 impl<'a> VirtIOConsole<'a> {
     /// Create a new VirtIO-Console driver.
     pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
+        let mut negotiated_features = Features::empty();
         header.begin_init(|features| {
             let features = Features::from_bits_truncate(features);
             info!("Device features {:?}", features);
-            let supported_features = Features::empty();
-            (features & supported_features).bits()
+            let supported_features = Features::MULTIPORT;
+            negotiated_features = features & supported_features;
+            negotiated_features.bits()
         });
-        let config = unsafe { &mut *(header.config_space() as *mut Config) };
+        let config = header.config::<Config>();
         info!("Config: {:?}", config);
-        let receiveq = VirtQueue::new(header, QUEUE_RECEIVEQ_PORT_0, 2)?;
-        let transmitq = VirtQueue::new(header, QUEUE_TRANSMITQ_PORT_0, 2)?;
+        // the max_nr_ports field is only valid if VIRTIO_CONSOLE_F_MULTIPORT is negotiated
+        let nr_ports = if negotiated_features.contains(Features::MULTIPORT) {
+            config.max_nr_ports.read().min(MAX_PORTS)
+        } else {
+            1
+        };
+        let receiveq = VirtQueue::new(header, QUEUE_RECEIVEQ_PORT_0, QUEUE_SIZE as u16)?;
+        let transmitq = VirtQueue::new(header, QUEUE_TRANSMITQ_PORT_0, QUEUE_SIZE as u16)?;
+        let control = if negotiated_features.contains(Features::MULTIPORT) {
+            Some(Control {
+                receiveq: VirtQueue::new(header, QUEUE_CONTROL_RECEIVEQ, QUEUE_SIZE as u16)?,
+                transmitq: VirtQueue::new(header, QUEUE_CONTROL_TRANSMITQ, QUEUE_SIZE as u16)?,
+                buf_dma: DMA::new(1)?,
+            })
+        } else {
+            None
+        };
+        let mut ports = Vec::new();
+        for port_id in 1..nr_ports {
+            let (rx, tx) = port_queues(port_id);
+            ports.push(Port {
+                receiveq: VirtQueue::new(header, rx, QUEUE_SIZE as u16)?,
+                transmitq: VirtQueue::new(header, tx, QUEUE_SIZE as u16)?,
+                added: false,
+                host_connected: false,
+                buf_dma: None,
+                cursor: 0,
+                pending_len: 0,
+            });
+        }
         let queue_buf_dma = DMA::new(1)?;
         let queue_buf_rx = unsafe { &mut queue_buf_dma.as_buf()[0..] };
         header.finish_init();
@@ -44,8 +122,14 @@ impl<'a> VirtIOConsole<'a> {
             queue_buf_rx,
             cursor: 0,
             pending_len: 0,
+            control,
+            ports,
         };
         console.poll_retrieve()?;
+        if console.control.is_some() {
+            console.poll_control()?;
+            console.send_control(0, VIRTIO_CONSOLE_DEVICE_READY, 1)?;
+        }
         Ok(console)
     }
     fn poll_retrieve(&mut self) -> Result<()> {
@@ -53,11 +137,15 @@ impl<'a> VirtIOConsole<'a> {
         Ok(())
     }
     /// Acknowledge interrupt.
+    ///
+    /// The control events of a multiport console are handled as well, and
+    /// return true if there is data for the port 0.
     pub fn ack_interrupt(&mut self) -> Result<bool> {
         let ack = self.header.ack_interrupt();
         if !ack {
             return Ok(false);
         }
+        self.handle_control()?;
         let mut flag = false;
         while let Ok((_token, len)) = self.receiveq.pop_used() {
             assert_eq!(flag, false);
@@ -87,15 +175,208 @@ impl<'a> VirtIOConsole<'a> {
     pub fn send(&mut self, chr: u8) -> Result<()> {
         let buf: [u8; 1] = [chr];
         self.transmitq.add(&[&buf], &[])?;
-        self.header.notify(QUEUE_TRANSMITQ_PORT_0 as u32);
+        if self.transmitq.should_notify() {
+            self.header.notify(QUEUE_TRANSMITQ_PORT_0 as u32);
+        }
         while !self.transmitq.can_pop() {
             spin_loop();
         }
         self.transmitq.pop_used()?;
         Ok(())
     }
+
+    /// Open a port other than the port 0 of a multiport console.
+    ///
+    /// Return [`Error::Unsupported`] if VIRTIO_CONSOLE_F_MULTIPORT is not
+    /// negotiated, [`Error::InvalidParam`] if there is no such port, or
+    /// [`Error::NotReady`] if the device has not added the port yet.
+    pub fn open_port(&mut self, port_id: u32) -> Result<ConsolePort<'_, 'a>> {
+        if self.control.is_none() {
+            return Err(Error::Unsupported);
+        }
+        if port_id == 0 || port_id as usize > self.ports.len() {
+            return Err(Error::InvalidParam);
+        }
+        self.handle_control()?;
+        let port = &mut self.ports[port_id as usize - 1];
+        if !port.added {
+            return Err(Error::NotReady);
+        }
+        if port.buf_dma.is_none() {
+            let buf_dma = DMA::new(1)?;
+            port.receiveq.add(&[], &[unsafe { buf_dma.as_buf() }])?;
+            port.buf_dma = Some(buf_dma);
+            if port.receiveq.should_notify() {
+                self.header.notify(port_queues(port_id).0 as u32);
+            }
+        }
+        self.send_control(port_id, VIRTIO_CONSOLE_PORT_OPEN, 1)?;
+        Ok(ConsolePort {
+            console: self,
+            port_id,
+        })
+    }
+
+    /// Give the control receive buffer to the device.
+    fn poll_control(&mut self) -> Result {
+        let control = self.control.as_mut().ok_or(Error::Unsupported)?;
+        control.receiveq.add(&[], &[unsafe { control.buf_dma.as_buf() }])?;
+        if control.receiveq.should_notify() {
+            self.header.notify(QUEUE_CONTROL_RECEIVEQ as u32);
+        }
+        Ok(())
+    }
+
+    /// Handle the control events sent by the device, if any.
+    fn handle_control(&mut self) -> Result {
+        loop {
+            let control = match self.control.as_mut() {
+                Some(control) => control,
+                None => return Ok(()),
+            };
+            let len = match control.receiveq.pop_used() {
+                Ok((_, len)) => len as usize,
+                Err(_) => return Ok(()),
+            };
+            // Safety: the buffer has been used by the device.
+            let buf = unsafe { control.buf_dma.as_buf() };
+            if len >= size_of::<ControlMessage>() {
+                let msg = unsafe { (buf.as_ptr() as *const ControlMessage).read() };
+                self.handle_control_message(msg)?;
+            }
+            self.poll_control()?;
+        }
+    }
+
+    fn handle_control_message(&mut self, msg: ControlMessage) -> Result {
+        debug!("console control message: {:?}", msg);
+        let port = match msg.id {
+            0 => None,
+            id => self.ports.get_mut(id as usize - 1),
+        };
+        match msg.event {
+            VIRTIO_CONSOLE_DEVICE_ADD => {
+                // the port 0 is always set up, the ports beyond MAX_PORTS are not
+                let ready = match port {
+                    Some(port) => {
+                        port.added = true;
+                        true
+                    }
+                    None => msg.id == 0,
+                };
+                self.send_control(msg.id, VIRTIO_CONSOLE_PORT_READY, ready as u16)?;
+            }
+            // a console port is opened right away, as Linux does
+            VIRTIO_CONSOLE_CONSOLE_PORT => self.send_control(msg.id, VIRTIO_CONSOLE_PORT_OPEN, 1)?,
+            VIRTIO_CONSOLE_DEVICE_REMOVE => {
+                if let Some(port) = port {
+                    port.added = false;
+                    port.host_connected = false;
+                }
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                if let Some(port) = port {
+                    port.host_connected = msg.value != 0;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Send a control event to the device.
+    fn send_control(&mut self, id: u32, event: u16, value: u16) -> Result {
+        let control = self.control.as_mut().ok_or(Error::Unsupported)?;
+        let msg = ControlMessage { id, event, value };
+        control.transmitq.add(&[msg.as_buf()], &[])?;
+        if control.transmitq.should_notify() {
+            self.header.notify(QUEUE_CONTROL_TRANSMITQ as u32);
+        }
+        while !control.transmitq.can_pop() {
+            spin_loop();
+        }
+        control.transmitq.pop_used()?;
+        Ok(())
+    }
+}
+
+/// A port of a multiport console, opened by [`VirtIOConsole::open_port`].
+pub struct ConsolePort<'c, 'a> {
+    console: &'c mut VirtIOConsole<'a>,
+    port_id: u32,
 }
 
+impl ConsolePort<'_, '_> {
+    /// The id of the port.
+    pub fn id(&self) -> u32 {
+        self.port_id
+    }
+
+    /// Whether the host has connected to the port.
+    pub fn host_connected(&mut self) -> Result<bool> {
+        self.console.handle_control()?;
+        Ok(self.console.ports[self.port_id as usize - 1].host_connected)
+    }
+
+    /// Try get char.
+    pub fn recv(&mut self) -> Result<Option<u8>> {
+        let port = &mut self.console.ports[self.port_id as usize - 1];
+        if port.cursor == port.pending_len {
+            match port.receiveq.pop_used() {
+                Ok((_, len)) if len != 0 => {
+                    port.cursor = 0;
+                    port.pending_len = len as usize;
+                }
+                _ => return Ok(None),
+            }
+        }
+        // Safety: the buffer has been allocated when the port was opened.
+        let buf = unsafe { port.buf_dma.as_ref().unwrap().as_buf() };
+        let ch = buf[port.cursor];
+        port.cursor += 1;
+        if port.cursor == port.pending_len {
+            port.receiveq.add(&[], &[buf])?;
+            if port.receiveq.should_notify() {
+                self.console.header.notify(port_queues(self.port_id).0 as u32);
+            }
+        }
+        Ok(Some(ch))
+    }
+
+    /// Put a char onto the port.
+    pub fn send(&mut self, chr: u8) -> Result<()> {
+        let port = &mut self.console.ports[self.port_id as usize - 1];
+        let buf: [u8; 1] = [chr];
+        port.transmitq.add(&[&buf], &[])?;
+        if port.transmitq.should_notify() {
+            self.console.header.notify(port_queues(self.port_id).1 as u32);
+        }
+        while !port.transmitq.can_pop() {
+            spin_loop();
+        }
+        port.transmitq.pop_used()?;
+        Ok(())
+    }
+}
+
+/// A control event of a multiport console.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct ControlMessage {
+    id: u32,
+    event: u16,
+    value: u16,
+}
+
+unsafe impl AsBuf for ControlMessage {}
+
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+const VIRTIO_CONSOLE_DEVICE_ADD: u16 = 1;
+const VIRTIO_CONSOLE_DEVICE_REMOVE: u16 = 2;
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+
 #[repr(C)]
 struct Config {
     cols: ReadOnly<u16>,
@@ -104,6 +385,8 @@ struct Config {
     emerg_wr: WriteOnly<u32>,
 }
 
+unsafe impl ConfigStruct for Config {}
+
 impl fmt::Debug for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Config")
@@ -137,3 +420,16 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_layout() {
+        assert_eq!(offset_of!(Config, cols), 0);
+        assert_eq!(offset_of!(Config, rows), 2);
+        assert_eq!(offset_of!(Config, max_nr_ports), 4);
+        assert_eq!(offset_of!(Config, emerg_wr), 8);
+    }
+}
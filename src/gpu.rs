@@ -15,14 +15,18 @@ use volatile::{ReadOnly, Volatile, WriteOnly};
 pub struct VirtIOGpu<'a> {
     header: &'static mut VirtIOHeader,
     rect: Rect,
+    /// The number of scanouts supported by the device.
+    num_scanouts: u32,
     /// DMA area of frame buffer.
     frame_buffer_dma: Option<DMA>,
     /// DMA area of cursor image buffer.
     cursor_buffer_dma: Option<DMA>,
     /// Queue for sending control commands.
-    control_queue: VirtQueue<'a>,
+    control_queue: VirtQueue<'a, QUEUE_SIZE>,
     /// Queue for sending cursor commands.
-    cursor_queue: VirtQueue<'a>,
+    cursor_queue: VirtQueue<'a, QUEUE_SIZE>,
+    /// Position of the cursor.
+    cursor_pos: (u32, u32),
     /// Queue buffer DMA
     queue_buf_dma: DMA,
     /// Send buffer for queue.
@@ -31,6 +35,16 @@ pub struct VirtIOGpu<'a> {
     queue_buf_recv: &'a mut [u8],
 }
 
+/// The framebuffer set up by [`VirtIOGpu::setup_framebuffer`].
+pub struct FrameBuffer<'a> {
+    /// The framebuffer, in 32 bits per pixel.
+    pub buf: &'a mut [u8],
+    /// The physical address of the framebuffer, aligned to a page.
+    pub paddr: usize,
+    /// The size of the framebuffer in bytes, a multiple of the page size.
+    pub size: usize,
+}
+
 impl VirtIOGpu<'_> {
     /// Create a new VirtIO-Gpu driver.
     pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
@@ -42,11 +56,13 @@ impl VirtIOGpu<'_> {
         });
 
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut Config) };
+        let config = header.config::<Config>();
         info!("Config: {:?}", config);
+        // the display info has room for MAX_SCANOUTS only, the spec's maximum
+        let num_scanouts = config.num_scanouts.read().min(MAX_SCANOUTS as u32);
 
-        let control_queue = VirtQueue::new(header, QUEUE_TRANSMIT, 2)?;
-        let cursor_queue = VirtQueue::new(header, QUEUE_CURSOR, 2)?;
+        let control_queue = VirtQueue::new(header, QUEUE_TRANSMIT, QUEUE_SIZE as u16)?;
+        let cursor_queue = VirtQueue::new(header, QUEUE_CURSOR, QUEUE_SIZE as u16)?;
 
         let queue_buf_dma = DMA::new(2)?;
         let queue_buf_send = unsafe { &mut queue_buf_dma.as_buf()[..PAGE_SIZE] };
@@ -59,8 +75,10 @@ impl VirtIOGpu<'_> {
             frame_buffer_dma: None,
             cursor_buffer_dma: None,
             rect: Rect::default(),
+            num_scanouts,
             control_queue,
             cursor_queue,
+            cursor_pos: (0, 0),
             queue_buf_dma,
             queue_buf_send,
             queue_buf_recv,
@@ -77,33 +95,72 @@ impl VirtIOGpu<'_> {
         (self.rect.width, self.rect.height)
     }
 
-    /// Setup framebuffer
-    pub fn setup_framebuffer(&mut self) -> Result<&mut [u8]> {
+    /// Setup framebuffer, return it with its physical address and size.
+    ///
+    /// The framebuffer is only written by the driver and read by the device
+    /// on flush, so it should be treated as write-combined memory: the caller
+    /// may remap `paddr` write-combining and use the new mapping instead of
+    /// `buf`, which is left with the default caching attribute.
+    pub fn setup_framebuffer(&mut self) -> Result<FrameBuffer<'_>> {
         // get display info
         let display_info = self.get_display_info()?;
         info!("=> {:?}", display_info);
-        self.rect = display_info.rect;
+        let rect = display_info.pmodes[SCANOUT_ID as usize].rect;
+        self.rect = rect;
 
         // create resource 2d
-        self.resource_create_2d(
-            RESOURCE_ID_FB,
-            display_info.rect.width,
-            display_info.rect.height,
-        )?;
+        self.resource_create_2d(RESOURCE_ID_FB, rect.width, rect.height)?;
 
         // alloc continuous pages for the frame buffer
-        let size = display_info.rect.width * display_info.rect.height * 4;
+        let size = rect.width * rect.height * 4;
         let frame_buffer_dma = DMA::new(pages(size as usize))?;
 
         // resource_attach_backing
         self.resource_attach_backing(RESOURCE_ID_FB, frame_buffer_dma.paddr() as u64, size)?;
 
         // map frame buffer to screen
-        self.set_scanout(display_info.rect, SCANOUT_ID, RESOURCE_ID_FB)?;
+        self.set_scanout(SCANOUT_ID, RESOURCE_ID_FB, rect)?;
 
         let buf = unsafe { frame_buffer_dma.as_buf() };
+        let paddr = frame_buffer_dma.paddr();
         self.frame_buffer_dma = Some(frame_buffer_dma);
-        Ok(buf)
+        Ok(FrameBuffer {
+            size: buf.len(),
+            buf,
+            paddr,
+        })
+    }
+
+    /// Get the number of scanouts (aka heads) supported by the device.
+    pub fn scanout_count(&self) -> usize {
+        self.num_scanouts as usize
+    }
+
+    /// Get the preferred position and resolution of a scanout.
+    pub fn scanout_rect(&mut self, scanout_id: u32) -> Result<Rect> {
+        if scanout_id >= self.num_scanouts {
+            return Err(Error::InvalidParam);
+        }
+        let display_info = self.get_display_info()?;
+        Ok(display_info.pmodes[scanout_id as usize].rect)
+    }
+
+    /// Display the area `rect` of a resource on a scanout.
+    ///
+    /// The framebuffer set up by [`VirtIOGpu::setup_framebuffer`] can be
+    /// displayed on another scanout with its resource ID [`RESOURCE_ID_FB`].
+    /// A resource ID of 0 disables the scanout.
+    pub fn set_scanout(&mut self, scanout_id: u32, resource_id: u32, rect: Rect) -> Result {
+        if scanout_id >= self.num_scanouts {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: CtrlHeader = self.request(SetScanout {
+            header: CtrlHeader::with_type(Command::SetScanout),
+            rect,
+            scanout_id,
+            resource_id,
+        })?;
+        rsp.check_type(Command::OkNodata)
     }
 
     /// Flush framebuffer to screen.
@@ -135,22 +192,26 @@ impl VirtIOGpu<'_> {
         self.resource_create_2d(RESOURCE_ID_CURSOR, CURSOR_RECT.width, CURSOR_RECT.height)?;
         self.resource_attach_backing(RESOURCE_ID_CURSOR, cursor_buffer_dma.paddr() as u64, size)?;
         self.transfer_to_host_2d(CURSOR_RECT, 0, RESOURCE_ID_CURSOR)?;
-        self.update_cursor(
-            RESOURCE_ID_CURSOR,
-            SCANOUT_ID,
-            pos_x,
-            pos_y,
-            hot_x,
-            hot_y,
-            false,
-        )?;
+        self.cursor_command(Command::UpdateCursor, RESOURCE_ID_CURSOR, (pos_x, pos_y), (hot_x, hot_y))?;
+        self.cursor_pos = (pos_x, pos_y);
         self.cursor_buffer_dma = Some(cursor_buffer_dma);
         Ok(())
     }
 
+    /// Update the pointer shape to the image of a resource, at the current
+    /// position of the pointer.
+    ///
+    /// The device does not report a maximum cursor size, the resource must be
+    /// a 64x64 image with its backing attached, like the one created by
+    /// [`VirtIOGpu::setup_cursor`]. A resource ID of 0 hides the pointer.
+    pub fn update_cursor(&mut self, resource_id: u32, hot_x: u32, hot_y: u32) -> Result {
+        self.cursor_command(Command::UpdateCursor, resource_id, self.cursor_pos, (hot_x, hot_y))
+    }
+
     /// Move the pointer without updating the shape.
     pub fn move_cursor(&mut self, pos_x: u32, pos_y: u32) -> Result {
-        self.update_cursor(RESOURCE_ID_CURSOR, SCANOUT_ID, pos_x, pos_y, 0, 0, true)?;
+        self.cursor_command(Command::MoveCursor, RESOURCE_ID_CURSOR, (pos_x, pos_y), (0, 0))?;
+        self.cursor_pos = (pos_x, pos_y);
         Ok(())
     }
 }
@@ -163,7 +224,9 @@ impl VirtIOGpu<'_> {
         }
         self.control_queue
             .add(&[self.queue_buf_send], &[self.queue_buf_recv])?;
-        self.header.notify(QUEUE_TRANSMIT as u32);
+        if self.control_queue.should_notify() {
+            self.header.notify(QUEUE_TRANSMIT as u32);
+        }
         while !self.control_queue.can_pop() {
             spin_loop();
         }
@@ -177,7 +240,9 @@ impl VirtIOGpu<'_> {
             (self.queue_buf_send.as_mut_ptr() as *mut Req).write(req);
         }
         self.cursor_queue.add(&[self.queue_buf_send], &[])?;
-        self.header.notify(QUEUE_CURSOR as u32);
+        if self.cursor_queue.should_notify() {
+            self.header.notify(QUEUE_CURSOR as u32);
+        }
         while !self.cursor_queue.can_pop() {
             spin_loop();
         }
@@ -202,16 +267,6 @@ impl VirtIOGpu<'_> {
         rsp.check_type(Command::OkNodata)
     }
 
-    fn set_scanout(&mut self, rect: Rect, scanout_id: u32, resource_id: u32) -> Result {
-        let rsp: CtrlHeader = self.request(SetScanout {
-            header: CtrlHeader::with_type(Command::SetScanout),
-            rect,
-            scanout_id,
-            resource_id,
-        })?;
-        rsp.check_type(Command::OkNodata)
-    }
-
     fn resource_flush(&mut self, rect: Rect, resource_id: u32) -> Result {
         let rsp: CtrlHeader = self.request(ResourceFlush {
             header: CtrlHeader::with_type(Command::ResourceFlush),
@@ -245,31 +300,26 @@ impl VirtIOGpu<'_> {
         rsp.check_type(Command::OkNodata)
     }
 
-    fn update_cursor(
+    /// Send an UPDATE_CURSOR or a MOVE_CURSOR `command` on the cursor queue,
+    /// with the pointer at `pos` of the scanout and its hot spot at `hot`.
+    fn cursor_command(
         &mut self,
+        command: Command,
         resource_id: u32,
-        scanout_id: u32,
-        pos_x: u32,
-        pos_y: u32,
-        hot_x: u32,
-        hot_y: u32,
-        is_move: bool,
+        pos: (u32, u32),
+        hot: (u32, u32),
     ) -> Result {
         self.cursor_request(UpdateCursor {
-            header: if is_move {
-                CtrlHeader::with_type(Command::MoveCursor)
-            } else {
-                CtrlHeader::with_type(Command::UpdateCursor)
-            },
+            header: CtrlHeader::with_type(command),
             pos: CursorPos {
-                scanout_id,
-                x: pos_x,
-                y: pos_y,
+                scanout_id: SCANOUT_ID,
+                x: pos.0,
+                y: pos.1,
                 _padding: 0,
             },
             resource_id,
-            hot_x,
-            hot_y,
+            hot_x: hot.0,
+            hot_y: hot.1,
             _padding: 0,
         })
     }
@@ -289,6 +339,8 @@ struct Config {
     num_scanouts: Volatile<u32>,
 }
 
+unsafe impl ConfigStruct for Config {}
+
 impl fmt::Debug for Config {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Config")
@@ -388,19 +440,34 @@ impl CtrlHeader {
     }
 }
 
+/// A rectangle area on a scanout or in a resource.
 #[repr(C)]
 #[derive(Debug, Copy, Clone, Default)]
-struct Rect {
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
+pub struct Rect {
+    /// The horizontal offset.
+    pub x: u32,
+    /// The vertical offset.
+    pub y: u32,
+    /// The width.
+    pub width: u32,
+    /// The height.
+    pub height: u32,
 }
 
+/// The maximum number of scanouts.
+const MAX_SCANOUTS: usize = 16;
+
 #[repr(C)]
 #[derive(Debug)]
 struct RespDisplayInfo {
     header: CtrlHeader,
+    pmodes: [DisplayOne; MAX_SCANOUTS],
+}
+
+/// The preferred mode of a scanout.
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct DisplayOne {
     rect: Rect,
     enabled: u32,
     flags: u32,
@@ -483,9 +550,12 @@ struct UpdateCursor {
 
 const QUEUE_TRANSMIT: usize = 0;
 const QUEUE_CURSOR: usize = 1;
+/// The size of each queue.
+const QUEUE_SIZE: usize = 2;
 
 const SCANOUT_ID: u32 = 0;
-const RESOURCE_ID_FB: u32 = 0xbabe;
+/// The resource ID of the framebuffer.
+pub const RESOURCE_ID_FB: u32 = 0xbabe;
 const RESOURCE_ID_CURSOR: u32 = 0xdade;
 
 const CURSOR_RECT: Rect = Rect {
@@ -494,3 +564,15 @@ const CURSOR_RECT: Rect = Rect {
     width: 64,
     height: 64,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_layout() {
+        assert_eq!(offset_of!(Config, events_read), 0);
+        assert_eq!(offset_of!(Config, events_clear), 4);
+        assert_eq!(offset_of!(Config, num_scanouts), 8);
+    }
+}
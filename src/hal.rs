@@ -2,6 +2,8 @@ use super::*;
 
 pub type VirtAddr = usize;
 pub type PhysAddr = usize;
+/// I/O virtual address, which the device uses behind an IOMMU.
+pub type IoVirtAddr = usize;
 
 pub struct DMA {
     paddr: u32,
@@ -20,6 +22,23 @@ impl DMA {
         })
     }
 
+    /// Allocate DMA memory below 4GB, for devices which ignore the high 32
+    /// bits of the addresses.
+    pub fn new_dma32(pages: usize) -> Result<Self> {
+        let paddr = unsafe { virtio_dma_alloc32(pages) };
+        if paddr == 0 {
+            return Err(Error::DmaError);
+        }
+        if paddr as u64 + (PAGE_SIZE * pages) as u64 > 1 << 32 {
+            unsafe { virtio_dma_dealloc(paddr, pages) };
+            return Err(Error::DmaError);
+        }
+        Ok(DMA {
+            paddr: paddr as u32,
+            pages: pages as u32,
+        })
+    }
+
     pub fn paddr(&self) -> usize {
         self.paddr as usize
     }
@@ -39,6 +58,49 @@ impl DMA {
     }
 }
 
+/// A DMA region which may be smaller than a page, with its own alignment,
+/// so that the HAL may place several of them in a page.
+pub struct DMARegion {
+    paddr: PhysAddr,
+    size: usize,
+    align: usize,
+}
+
+impl DMARegion {
+    /// Allocate a region of `size` bytes aligned to `align`, which must be a
+    /// power of 2.
+    pub fn new(size: usize, align: usize) -> Result<Self> {
+        if !align.is_power_of_two() {
+            return Err(Error::InvalidParam);
+        }
+        let paddr = unsafe { virtio_dma_alloc_aligned(size, align) };
+        if paddr == 0 {
+            return Err(Error::DmaError);
+        }
+        if paddr % align != 0 {
+            // the HAL ignored the alignment, give the region back to it
+            unsafe { virtio_dma_dealloc_aligned(paddr, size, align) };
+            return Err(Error::DmaError);
+        }
+        Ok(DMARegion { paddr, size, align })
+    }
+
+    pub fn paddr(&self) -> usize {
+        self.paddr
+    }
+
+    pub fn vaddr(&self) -> usize {
+        phys_to_virt(self.paddr)
+    }
+}
+
+impl Drop for DMARegion {
+    fn drop(&mut self) {
+        let err = unsafe { virtio_dma_dealloc_aligned(self.paddr, self.size, self.align) };
+        assert_eq!(err, 0, "failed to deallocate DMA");
+    }
+}
+
 impl Drop for DMA {
     fn drop(&mut self) {
         let err = unsafe { virtio_dma_dealloc(self.paddr as usize, self.pages as usize) };
@@ -54,9 +116,105 @@ pub fn virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
     unsafe { virtio_virt_to_phys(vaddr) }
 }
 
+/// Translate a virtual address to the I/O virtual address of the device.
+///
+/// It is used instead of [`virt_to_phys`] once VIRTIO_F_ACCESS_PLATFORM
+/// has been negotiated.
+pub fn virt_to_iova(vaddr: VirtAddr) -> IoVirtAddr {
+    unsafe { virtio_virt_to_iova(vaddr) }
+}
+
 extern "C" {
     fn virtio_dma_alloc(pages: usize) -> PhysAddr;
+    fn virtio_dma_alloc32(pages: usize) -> PhysAddr;
     fn virtio_dma_dealloc(paddr: PhysAddr, pages: usize) -> i32;
+    fn virtio_dma_alloc_aligned(size: usize, align: usize) -> PhysAddr;
+    fn virtio_dma_dealloc_aligned(paddr: PhysAddr, size: usize, align: usize) -> i32;
     fn virtio_phys_to_virt(paddr: PhysAddr) -> VirtAddr;
     fn virtio_virt_to_phys(vaddr: VirtAddr) -> PhysAddr;
+    fn virtio_virt_to_iova(vaddr: VirtAddr) -> IoVirtAddr;
+}
+
+/// The HAL of the unit tests, with memory identity mapped from the heap.
+///
+/// Pages are taken from a pool instead, at physical addresses which fit in
+/// the 32 bits of `DMA`, and are never reused.
+#[cfg(test)]
+mod test_hal {
+    use super::*;
+    use alloc::alloc::{alloc_zeroed, dealloc, Layout};
+    use core::cell::UnsafeCell;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+
+    const POOL_PAGES: usize = 256;
+    /// The physical address of the pool, below the addresses of the heap.
+    const POOL_PADDR: PhysAddr = 0x1000_0000;
+
+    #[repr(C, align(4096))]
+    struct Pool(UnsafeCell<[[u8; PAGE_SIZE]; POOL_PAGES]>);
+
+    // The pages are only accessed through the addresses handed out.
+    unsafe impl Sync for Pool {}
+
+    static POOL: Pool = Pool(UnsafeCell::new([[0; PAGE_SIZE]; POOL_PAGES]));
+    static NEXT_PAGE: AtomicUsize = AtomicUsize::new(0);
+
+    fn pool_vaddr() -> VirtAddr {
+        POOL.0.get() as VirtAddr
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_dma_alloc(pages: usize) -> PhysAddr {
+        let first = NEXT_PAGE.fetch_add(pages, Ordering::Relaxed);
+        if first + pages > POOL_PAGES {
+            return 0;
+        }
+        POOL_PADDR + first * PAGE_SIZE
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_dma_alloc32(pages: usize) -> PhysAddr {
+        virtio_dma_alloc(pages)
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_dma_dealloc(_paddr: PhysAddr, _pages: usize) -> i32 {
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_dma_alloc_aligned(size: usize, align: usize) -> PhysAddr {
+        match Layout::from_size_align(size, align) {
+            Ok(layout) if size != 0 => unsafe { alloc_zeroed(layout) as PhysAddr },
+            _ => 0,
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_dma_dealloc_aligned(paddr: PhysAddr, size: usize, align: usize) -> i32 {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        unsafe { dealloc(paddr as *mut u8, layout) };
+        0
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_phys_to_virt(paddr: PhysAddr) -> VirtAddr {
+        match paddr.checked_sub(POOL_PADDR) {
+            Some(offset) if offset < POOL_PAGES * PAGE_SIZE => pool_vaddr() + offset,
+            _ => paddr,
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_virt_to_phys(vaddr: VirtAddr) -> PhysAddr {
+        match vaddr.checked_sub(pool_vaddr()) {
+            Some(offset) if offset < POOL_PAGES * PAGE_SIZE => POOL_PADDR + offset,
+            _ => vaddr,
+        }
+    }
+
+    #[no_mangle]
+    extern "C" fn virtio_virt_to_iova(vaddr: VirtAddr) -> IoVirtAddr {
+        virtio_virt_to_phys(vaddr)
+    }
 }
@@ -1,10 +1,15 @@
+use crate::queue::Transport;
 use crate::PAGE_SIZE;
 use bitflags::*;
+use core::fmt;
 use volatile::{ReadOnly, Volatile, WriteOnly};
 
-/// MMIO Device Legacy Register Interface.
+/// MMIO Device Register Interface, of version 1 (legacy) or 2 (modern).
 ///
-/// Ref: 4.2.4 Legacy interface
+/// The two versions share the layout, except for the registers used to set
+/// up the queues.
+///
+/// Ref: 4.2.2 MMIO Device Register Layout, 4.2.4 Legacy interface
 #[repr(C)]
 pub struct VirtIOHeader {
     /// Magic value
@@ -146,17 +151,28 @@ pub struct VirtIOHeader {
 }
 
 impl VirtIOHeader {
-    /// Verify a valid header.
+    /// Verify a valid header, of either the legacy or the modern interface.
     pub fn verify(&self) -> bool {
-        self.magic.read() == 0x7472_6976 && self.version.read() == 1 && self.device_id.read() != 0
+        self.magic.read() == 0x7472_6976
+            && matches!(self.version.read(), 1 | 2)
+            && self.device_id.read() != 0
+    }
+
+    /// Get the device version, 1 for the legacy interface and 2 for the
+    /// modern one.
+    pub fn version(&self) -> u32 {
+        self.version.read()
+    }
+
+    /// Whether the device only provides the legacy interface, which sets up
+    /// the queues through QueuePFN.
+    pub fn is_legacy(&self) -> bool {
+        self.version.read() == 1
     }
 
     /// Get the device type.
     pub fn device_type(&self) -> DeviceType {
-        match self.device_id.read() {
-            x @ 1..=13 | x @ 16..=24 => unsafe { core::mem::transmute(x as u8) },
-            _ => DeviceType::Invalid,
-        }
+        DeviceType::from(self.device_id.read())
     }
 
     /// Get the vendor ID.
@@ -164,18 +180,30 @@ impl VirtIOHeader {
         self.vendor_id.read()
     }
 
-    /// Begin initializing the device.
+    /// Begin initializing the device, return the features written to it.
+    ///
+    /// VIRTIO_F_VERSION_1 is added to those returned by `negotiate_features`
+    /// for a modern device, so the layouts which depend on it, e.g. the net
+    /// header, must be sized from the returned features.
     ///
     /// Ref: virtio 3.1.1 Device Initialization
-    pub fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) {
+    pub fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) -> u64 {
         self.status.write(DeviceStatus::ACKNOWLEDGE);
         self.status.write(DeviceStatus::DRIVER);
 
         let features = self.read_device_features();
-        self.write_driver_features(negotiate_features(features));
+        let mut driver_features = negotiate_features(features);
+        if !self.is_legacy() {
+            // a modern device may refuse to work with a legacy driver
+            driver_features |= features & VIRTIO_F_VERSION_1;
+        }
+        self.write_driver_features(driver_features);
         self.status.write(DeviceStatus::FEATURES_OK);
 
-        self.guest_page_size.write(PAGE_SIZE as u32);
+        if self.is_legacy() {
+            self.guest_page_size.write(PAGE_SIZE as u32);
+        }
+        driver_features
     }
 
     /// Finish initializing the device.
@@ -183,6 +211,12 @@ impl VirtIOHeader {
         self.status.write(DeviceStatus::DRIVER_OK);
     }
 
+    /// Whether the device has set DEVICE_NEEDS_RESET, after an error it
+    /// cannot recover from.
+    pub fn needs_reset(&self) -> bool {
+        self.status.read().contains(DeviceStatus::DEVICE_NEEDS_RESET)
+    }
+
     /// Read device features.
     fn read_device_features(&mut self) -> u64 {
         self.device_features_sel.write(0); // device features [0, 32)
@@ -200,23 +234,72 @@ impl VirtIOHeader {
         self.driver_features.write((driver_features >> 32) as u32);
     }
 
-    /// Set queue.
-    pub fn queue_set(&mut self, queue: u32, size: u32, align: u32, pfn: u32) {
+    /// Set queue, whose rings are at the given physical addresses.
+    ///
+    /// A legacy device is given the page frame number of the descriptor
+    /// table, so the rings must be contiguous with the used ring aligned to
+    /// `align`, and a modern device is given the address of each ring.
+    pub fn queue_set(
+        &mut self,
+        queue: u32,
+        size: u32,
+        align: u32,
+        desc_paddr: usize,
+        avail_paddr: usize,
+        used_paddr: usize,
+    ) {
+        if self.is_legacy() {
+            self.queue_set_legacy(queue, size, align, (desc_paddr / PAGE_SIZE) as u32);
+        } else {
+            self.queue_set_modern(queue, size, desc_paddr, avail_paddr, used_paddr);
+        }
+    }
+
+    /// Set queue through QueuePFN.
+    ///
+    /// Ref: virtio 4.2.4 Legacy interface
+    fn queue_set_legacy(&mut self, queue: u32, size: u32, align: u32, pfn: u32) {
         self.queue_sel.write(queue);
         self.queue_num.write(size);
         self.queue_align.write(align);
         self.queue_pfn.write(pfn);
     }
 
+    /// Set queue through the address registers of each ring, and mark it
+    /// ready.
+    ///
+    /// Ref: virtio 4.2.3.2 Virtqueue Configuration
+    fn queue_set_modern(&mut self, queue: u32, size: u32, desc: usize, avail: usize, used: usize) {
+        self.queue_sel.write(queue);
+        self.queue_num.write(size);
+        self.queue_desc_low.write(desc as u32);
+        self.queue_desc_high.write((desc as u64 >> 32) as u32);
+        self.queue_avail_low.write(avail as u32);
+        self.queue_avail_high.write((avail as u64 >> 32) as u32);
+        self.queue_used_low.write(used as u32);
+        self.queue_used_high.write((used as u64 >> 32) as u32);
+        self.queue_ready.write(1);
+    }
+
     /// Get guest physical page number of the virtual queue.
+    ///
+    /// Only the legacy interface has QueuePFN, it reads 0 otherwise.
     pub fn queue_physical_page_number(&mut self, queue: u32) -> u32 {
+        if !self.is_legacy() {
+            return 0;
+        }
         self.queue_sel.write(queue);
         self.queue_pfn.read()
     }
 
     /// Whether the queue is in used.
     pub fn queue_used(&mut self, queue: u32) -> bool {
-        self.queue_physical_page_number(queue) != 0
+        if self.is_legacy() {
+            self.queue_physical_page_number(queue) != 0
+        } else {
+            self.queue_sel.write(queue);
+            self.queue_ready.read() != 0
+        }
     }
 
     /// Get the max size of queue.
@@ -244,6 +327,23 @@ impl VirtIOHeader {
     pub fn config_space(&self) -> *mut u64 {
         (self as *const _ as usize + CONFIG_SPACE_OFFSET) as _
     }
+
+    /// Map the device-specific configuration as `T`.
+    pub fn config<T: ConfigStruct>(&mut self) -> &mut T {
+        // Safety: `T` matches the layout of the configuration space.
+        unsafe { &mut *(self.config_space() as *mut T) }
+    }
+}
+
+impl Transport for VirtIOHeader {
+    // the MMIO drivers do not negotiate VIRTIO_F_NOTIFICATION_DATA
+    fn notify(&mut self, queue: u16, _avail_idx: u16) {
+        VirtIOHeader::notify(self, queue as u32);
+    }
+
+    fn needs_reset(&self) -> bool {
+        VirtIOHeader::needs_reset(self)
+    }
 }
 
 bitflags! {
@@ -277,6 +377,79 @@ bitflags! {
 
 const CONFIG_SPACE_OFFSET: usize = 0x100;
 
+/// Feature bit indicating compliance with the modern interface.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A layout of the device-specific configuration space.
+///
+/// Each implementation should check its field offsets against the spec
+/// with compile-time assertions.
+///
+/// # Safety
+///
+/// The type must be `#[repr(C)]` and match the layout defined by the spec
+/// for its device type, since it is mapped over the configuration space.
+pub unsafe trait ConfigStruct {}
+
+/// Names of the device independent feature bits, reserved for the transport.
+///
+/// Ref: virtio 6 Reserved Feature Bits
+const TRANSPORT_FEATURES: &[(u64, &str)] = &[
+    (1 << 24, "NOTIFY_ON_EMPTY"),
+    (1 << 27, "ANY_LAYOUT"),
+    (1 << 28, "RING_INDIRECT_DESC"),
+    (1 << 29, "RING_EVENT_IDX"),
+    (1 << 30, "UNUSED"),
+    (1 << 32, "VERSION_1"),
+    (1 << 33, "ACCESS_PLATFORM"),
+    (1 << 34, "RING_PACKED"),
+    (1 << 35, "IN_ORDER"),
+    (1 << 36, "ORDER_PLATFORM"),
+    (1 << 37, "SR_IOV"),
+    (1 << 38, "NOTIFICATION_DATA"),
+    (1 << 39, "NOTIF_CONFIG_DATA"),
+    (1 << 40, "RING_RESET"),
+];
+
+/// The feature bits reserved for the transport, bits 24 to 40.
+const TRANSPORT_FEATURES_MASK: u64 = ((1 << 41) - 1) & !((1 << 24) - 1);
+
+/// Describe the transport feature bits in `bits` by name, ignoring the
+/// device specific ones, e.g. `RING_INDIRECT_DESC | VERSION_1`.
+pub fn describe_transport_features(bits: u64) -> impl fmt::Display {
+    TransportFeatures(bits & TRANSPORT_FEATURES_MASK)
+}
+
+struct TransportFeatures(u64);
+
+impl fmt::Display for TransportFeatures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut remaining = self.0;
+        let mut first = true;
+        for &(bit, name) in TRANSPORT_FEATURES {
+            if remaining & bit != 0 {
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                remaining &= !bit;
+                first = false;
+            }
+        }
+        if remaining != 0 {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{:#x}", remaining)?;
+            first = false;
+        }
+        if first {
+            f.write_str("(empty)")?;
+        }
+        Ok(())
+    }
+}
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Debug, Eq, PartialEq)]
@@ -306,3 +479,12 @@ pub enum DeviceType {
     IOMMU = 23,
     Memory = 24,
 }
+
+impl From<u32> for DeviceType {
+    fn from(device_id: u32) -> Self {
+        match device_id {
+            x @ 1..=13 | x @ 16..=24 => unsafe { core::mem::transmute::<u8, DeviceType>(x as u8) },
+            _ => DeviceType::Invalid,
+        }
+    }
+}
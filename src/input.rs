@@ -1,5 +1,6 @@
 use super::*;
 use alloc::boxed::Box;
+use alloc::string::String;
 use bitflags::*;
 use log::*;
 use volatile::{ReadOnly, WriteOnly};
@@ -11,8 +12,8 @@ use volatile::{ReadOnly, WriteOnly};
 /// making pass-through implementations on top of evdev easy.
 pub struct VirtIOInput<'a> {
     header: &'static mut VirtIOHeader,
-    event_queue: VirtQueue<'a>,
-    status_queue: VirtQueue<'a>,
+    event_queue: VirtQueue<'a, QUEUE_SIZE>,
+    status_queue: VirtQueue<'a, QUEUE_SIZE>,
     event_buf: Box<[InputEvent; 32]>,
 }
 
@@ -70,7 +71,7 @@ impl<'a> VirtIOInput<'a> {
         subsel: u8,
         out: &mut [u8],
     ) -> u8 {
-        let config = unsafe { &mut *(self.header.config_space() as *mut Config) };
+        let config = self.header.config::<Config>();
         config.select.write(select as u8);
         config.subsel.write(subsel);
         let size = config.size.read();
@@ -78,6 +79,76 @@ impl<'a> VirtIOInput<'a> {
         out[..size as usize].copy_from_slice(&data[..size as usize]);
         size
     }
+
+    /// Get the name of the device.
+    pub fn name(&mut self) -> String {
+        self.query_string(InputConfigSelect::IdName)
+    }
+
+    /// Get the serial number of the device.
+    pub fn serial(&mut self) -> String {
+        self.query_string(InputConfigSelect::IdSerial)
+    }
+
+    /// Get the input properties of the device, as INPUT_PROP_* bits.
+    pub fn prop_bits(&mut self) -> EvBits {
+        self.query_bitmap(InputConfigSelect::PropBits, 0)
+    }
+
+    /// Get the event codes supported for the event type `ev_type`, an EV_*
+    /// constant. The bitmap is empty if the event type is not supported.
+    pub fn supported_events(&mut self, ev_type: u8) -> EvBits {
+        self.query_bitmap(InputConfigSelect::EvBits, ev_type)
+    }
+
+    fn query_string(&mut self, select: InputConfigSelect) -> String {
+        let mut buf = [0u8; 128];
+        let size = self.query_config_select(select, 0, &mut buf) as usize;
+        String::from_utf8_lossy(&buf[..size]).into_owned()
+    }
+
+    fn query_bitmap(&mut self, select: InputConfigSelect, subsel: u8) -> EvBits {
+        let mut bits = EvBits {
+            bitmap: [0; 128],
+            size: 0,
+        };
+        bits.size = self.query_config_select(select, subsel, &mut bits.bitmap);
+        bits
+    }
+}
+
+/// A bitmap of event codes or input properties, as returned by
+/// [`VirtIOInput::supported_events`] and [`VirtIOInput::prop_bits`].
+#[derive(Clone, Copy)]
+pub struct EvBits {
+    bitmap: [u8; 128],
+    size: u8,
+}
+
+impl EvBits {
+    /// Whether the bit of `code` is set.
+    pub fn contains(&self, code: u16) -> bool {
+        let byte = code as usize / 8;
+        byte < self.size as usize && self.bitmap[byte] & (1 << (code % 8)) != 0
+    }
+
+    /// Whether no bit is set.
+    pub fn is_empty(&self) -> bool {
+        self.as_bytes().iter().all(|&byte| byte == 0)
+    }
+
+    /// The bitmap returned by the device.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bitmap[..self.size as usize]
+    }
+}
+
+impl core::fmt::Debug for EvBits {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list()
+            .entries((0..self.size as u16 * 8).filter(|&code| self.contains(code)))
+            .finish()
+    }
 }
 
 /// Select value used for [`VirtIOInput::query_config_select()`].
@@ -114,6 +185,8 @@ struct Config {
     data: ReadOnly<[u8; 128]>,
 }
 
+unsafe impl ConfigStruct for Config {}
+
 #[repr(C)]
 #[derive(Debug)]
 struct AbsInfo {
@@ -173,3 +246,16 @@ const QUEUE_STATUS: usize = 1;
 
 // a parameter that can change
 const QUEUE_SIZE: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_layout() {
+        assert_eq!(offset_of!(Config, select), 0);
+        assert_eq!(offset_of!(Config, subsel), 1);
+        assert_eq!(offset_of!(Config, size), 2);
+        assert_eq!(offset_of!(Config, data), 8);
+    }
+}
@@ -10,6 +10,23 @@ extern crate log;
 
 extern crate alloc;
 
+#[cfg(test)]
+extern crate std;
+
+/// The offset of a field in a struct, for the layout tests of the
+/// configuration structures, as `core::mem::offset_of!` is too recent for
+/// the toolchain of the examples.
+#[cfg(test)]
+macro_rules! offset_of {
+    ($ty:ty, $field:ident) => {{
+        let value = core::mem::MaybeUninit::<$ty>::uninit();
+        let base = value.as_ptr();
+        // Safety: the field is not read, only its address is taken.
+        let field = unsafe { core::ptr::addr_of!((*base).$field) };
+        field as usize - base as usize
+    }};
+}
+
 mod blk;
 mod console;
 mod gpu;
@@ -20,14 +37,20 @@ mod net;
 mod queue;
 mod pci;
 
-pub use self::blk::{BlkResp, RespStatus, VirtIOBlk};
-pub use self::pci::{VirtIOPCIHeader, VirtIOBlkPCI};
-pub use self::console::VirtIOConsole;
-pub use self::gpu::VirtIOGpu;
+pub use self::blk::{
+    BlkFeature, BlkZoneDescriptor, BlockDevice, BlkReqResp, BlkResp, ReqType, RequestData, RespStatus, VirtIOBlk,
+    ZoneInfo, ZoneModel,
+};
+pub use self::pci::{AckResult, BatchCompletion, InflightRequest, InterruptMode, InterruptOutcome, IsrStatus, NotifyData, QueueInterruptMap, ReadFuture, VirtIOPCICap, VirtIOPCIHeader, VirtIOPCIStructs, VirtIOBlkPCI, VirtIOBlkPCIBuilder, VirtIODevice, VirtIORngPCI, WriteFuture};
+pub use self::console::{ConsolePort, VirtIOConsole};
+pub use self::gpu::{FrameBuffer, Rect, VirtIOGpu, RESOURCE_ID_FB};
 pub use self::header::*;
-pub use self::input::{InputConfigSelect, InputEvent, VirtIOInput};
-pub use self::net::VirtIONet;
-use self::queue::VirtQueue;
+pub use self::input::{EvBits, InputConfigSelect, InputEvent, VirtIOInput};
+pub use self::net::{Gso, GsoCaps, GsoType, RssCaps, VirtIONet};
+pub use self::queue::{DescChainDump, DescDump, QueueDebugInfo, QueueRings, Transport};
+pub use self::queue::VirtQueue;
+#[cfg(feature = "stats")]
+pub use self::queue::VirtQueueStats;
 use core::mem::size_of;
 use hal::*;
 
@@ -46,6 +69,8 @@ pub type Result<T = ()> = core::result::Result<T, Error>;
 pub enum Error {
     /// The buffer is too small.
     BufferTooSmall,
+    /// There are not enough free descriptors in the queue.
+    QueueFull,
     /// The device is not ready.
     NotReady,
     /// The queue is already in use.
@@ -56,10 +81,33 @@ pub enum Error {
     DmaError,
     /// I/O Error
     IoError,
+    /// The device did not respond in time.
+    Timeout,
+    /// The operation is not supported by the device.
+    Unsupported,
+    /// The device only supports the legacy interface.
+    LegacyUnsupported,
+    /// The device is not of the type the driver expects.
+    WrongDeviceType,
+    /// The device does not implement the queue, or rejected it.
+    QueueUnavailable,
+    /// The device does not follow the virtio protocol, e.g. it has used a
+    /// buffer which was not made available.
+    ProtocolError,
+    /// A block read back does not match the checksum of the data written.
+    IntegrityCheckFailed,
+    /// The device has set DEVICE_NEEDS_RESET, it must be reset before it
+    /// can be used again.
+    NeedsReset,
+    /// The device has reached its maximum of open or active zones, some
+    /// have to be closed or finished first.
+    ZoneLimitReached,
+    /// The device has written out of the bounds of a response.
+    DeviceOverran,
 }
 
 /// Align `size` up to a page.
-fn align_up(size: usize) -> usize {
+const fn align_up(size: usize) -> usize {
     (size + PAGE_SIZE) & !(PAGE_SIZE - 1)
 }
 
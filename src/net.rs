@@ -1,6 +1,7 @@
 use core::mem::{size_of, MaybeUninit};
 
 use super::*;
+use alloc::vec::Vec;
 use bitflags::*;
 use core::hint::spin_loop;
 use log::*;
@@ -15,36 +16,129 @@ use volatile::{ReadOnly, Volatile};
 /// A third command queue is used to control advanced filtering features.
 pub struct VirtIONet<'a> {
     header: &'static mut VirtIOHeader,
-    mac: EthernetAddress,
-    recv_queue: VirtQueue<'a>,
-    send_queue: VirtQueue<'a>,
+    mac: Option<EthernetAddress>,
+    mtu: Option<u16>,
+    features: Features,
+    /// Whether receiving is paused by [`VirtIONet::pause_rx`].
+    rx_paused: bool,
+    recv_queue: VirtQueue<'a, QUEUE_SIZE>,
+    send_queue: VirtQueue<'a, QUEUE_SIZE>,
+    /// The receive-side scaling capabilities, if VIRTIO_NET_F_RSS has been
+    /// negotiated.
+    rss: Option<RssCaps>,
+    /// The receive queues of the other queue pairs, set up for RSS.
+    rss_recv_queues: Vec<VirtQueue<'a, QUEUE_SIZE>>,
+    /// The control queue, set up for RSS.
+    ctrl_queue: Option<VirtQueue<'a, CTRL_QUEUE_SIZE>>,
+}
+
+/// The receive-side scaling capabilities of a network device.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct RssCaps {
+    /// The maximum length of the hash key.
+    pub max_key_size: u8,
+    /// The maximum length of the indirection table.
+    pub max_indirection_table_len: u16,
+    /// The supported `VIRTIO_NET_RSS_HASH_TYPE_*` bits: IPv4, TCPv4, UDPv4,
+    /// IPv6, TCPv6, UDPv6 from bit 0, then the same with IPv6 extension
+    /// headers for IP, TCP and UDP.
+    pub supported_hash_types: u32,
+    /// The number of queue pairs, whose receive queues the packets can be
+    /// steered to.
+    pub queue_pairs: u16,
 }
 
 impl VirtIONet<'_> {
     /// Create a new VirtIO-Net driver.
     pub fn new(header: &'static mut VirtIOHeader) -> Result<Self> {
-        header.begin_init(|features| {
+        let mut negotiated_features = Features::empty();
+        let driver_features = header.begin_init(|features| {
             let features = Features::from_bits_truncate(features);
             info!("Device features {:?}", features);
-            let supported_features = Features::MAC | Features::STATUS;
-            (features & supported_features).bits()
+            let supported_features = Features::MAC
+                | Features::STATUS
+                | Features::MTU
+                | Features::CSUM
+                | Features::HOST_TSO4
+                | Features::HOST_TSO6
+                | Features::HOST_UFO
+                | Features::CTRL_VQ
+                | Features::RSS;
+            negotiated_features = features & supported_features;
+            // segmentation offload requires checksum offload, see virtio 5.1.3.1
+            if !negotiated_features.contains(Features::CSUM) {
+                negotiated_features.remove(Features::HOST_TSO4 | Features::HOST_TSO6 | Features::HOST_UFO);
+            }
+            // RSS is configured through the control queue, which is only
+            // used for it
+            if !negotiated_features.contains(Features::CTRL_VQ | Features::RSS) {
+                negotiated_features.remove(Features::CTRL_VQ | Features::RSS);
+            }
+            negotiated_features.bits()
         });
+        // VIRTIO_F_VERSION_1, added for a modern device, changes the header
+        negotiated_features = Features::from_bits_truncate(driver_features);
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut Config) };
-        let mac = config.mac.read();
-        debug!("Got MAC={:?}, status={:?}", mac, config.status.read());
+        let config = header.config::<Config>();
+        // the mac field is only valid if VIRTIO_NET_F_MAC is negotiated
+        let mac = if negotiated_features.contains(Features::MAC) {
+            Some(config.mac.read())
+        } else {
+            None
+        };
+        // the mtu field is only valid if VIRTIO_NET_F_MTU is negotiated
+        let mtu = if negotiated_features.contains(Features::MTU) {
+            Some(config.mtu.read())
+        } else {
+            None
+        };
+        debug!(
+            "Got MAC={:?}, status={:?}, MTU={:?}",
+            mac,
+            config.status.read(),
+            mtu
+        );
 
-        let queue_num = 2; // for simplicity
-        let recv_queue = VirtQueue::new(header, QUEUE_RECEIVE, queue_num)?;
-        let send_queue = VirtQueue::new(header, QUEUE_TRANSMIT, queue_num)?;
+        // the RSS fields are only valid if VIRTIO_NET_F_RSS is negotiated
+        let rss = if negotiated_features.contains(Features::RSS) {
+            Some(RssCaps {
+                max_key_size: config.rss_max_key_size.read(),
+                max_indirection_table_len: config.rss_max_indirection_table_length.read(),
+                supported_hash_types: config.supported_hash_types.read(),
+                queue_pairs: config.max_virtqueue_pairs.read().max(1),
+            })
+        } else {
+            None
+        };
+        debug!("RSS: {:?}", rss);
+
+        let recv_queue = VirtQueue::new(header, QUEUE_RECEIVE, QUEUE_SIZE as u16)?;
+        let send_queue = VirtQueue::new(header, QUEUE_TRANSMIT, QUEUE_SIZE as u16)?;
+        let mut rss_recv_queues = Vec::new();
+        let mut ctrl_queue = None;
+        if let Some(rss) = &rss {
+            // receiveqN is at 2(N-1), the only transmit queue used is the first
+            for pair in 1..rss.queue_pairs as usize {
+                rss_recv_queues.push(VirtQueue::new(header, QUEUE_RECEIVE + 2 * pair, QUEUE_SIZE as u16)?);
+            }
+            // a command is a chain of its header, its data and the ack
+            let ctrl_queue_idx = 2 * rss.queue_pairs as usize;
+            ctrl_queue = Some(VirtQueue::new(header, ctrl_queue_idx, CTRL_QUEUE_SIZE as u16)?);
+        }
 
         header.finish_init();
 
         Ok(VirtIONet {
             header,
             mac,
+            mtu,
+            features: negotiated_features,
+            rx_paused: false,
             recv_queue,
             send_queue,
+            rss,
+            rss_recv_queues,
+            ctrl_queue,
         })
     }
 
@@ -53,11 +147,80 @@ impl VirtIONet<'_> {
         self.header.ack_interrupt()
     }
 
-    /// Get MAC address.
-    pub fn mac(&self) -> EthernetAddress {
+    /// Get MAC address, if the device has given one.
+    pub fn mac_address(&self) -> Option<EthernetAddress> {
         self.mac
     }
 
+    /// Get the maximum MTU of the device, if it has given one.
+    pub fn mtu(&self) -> Option<u16> {
+        self.mtu
+    }
+
+    /// Get the segmentation offloads the device supports.
+    pub fn supported_gso(&self) -> GsoCaps {
+        let mut caps = GsoCaps::empty();
+        caps.set(GsoCaps::TCPV4, self.features.contains(Features::HOST_TSO4));
+        caps.set(GsoCaps::TCPV6, self.features.contains(Features::HOST_TSO6));
+        caps.set(GsoCaps::UDP, self.features.contains(Features::HOST_UFO));
+        caps
+    }
+
+    /// Get the receive-side scaling capabilities of the device, if
+    /// VIRTIO_NET_F_RSS has been negotiated.
+    pub fn rss_caps(&self) -> Option<RssCaps> {
+        self.rss
+    }
+
+    /// Configure receive-side scaling: the device hashes the `hash_types`
+    /// fields of the incoming packets with `key`, and steers each packet to
+    /// the receive queue of the queue pair the hash selects in
+    /// `indirection_table`, to be received by [`VirtIONet::recv_from`].
+    ///
+    /// Return [`Error::Unsupported`] if VIRTIO_NET_F_RSS has not been
+    /// negotiated, and [`Error::InvalidParam`] if the key or the table is too
+    /// long, if the length of the table is not a power of 2, if it names a
+    /// queue pair the device does not have, or if a hash type is not
+    /// supported. Return [`Error::IoError`] if the device rejects the
+    /// configuration.
+    /// Ref: VirtIO spec v1.2 section 5.1.6.5.7
+    pub fn configure_rss(&mut self, key: &[u8], indirection_table: &[u16], hash_types: u32) -> Result {
+        let rss = self.rss.ok_or(Error::Unsupported)?;
+        if key.len() > rss.max_key_size as usize
+            || !indirection_table.len().is_power_of_two()
+            || indirection_table.len() > rss.max_indirection_table_len as usize
+            || indirection_table.iter().any(|&pair| pair >= rss.queue_pairs)
+            || hash_types & !rss.supported_hash_types != 0
+        {
+            return Err(Error::InvalidParam);
+        }
+        let mut data = Vec::with_capacity(11 + 2 * indirection_table.len() + key.len());
+        data.extend_from_slice(&hash_types.to_le_bytes());
+        data.extend_from_slice(&(indirection_table.len() as u16 - 1).to_le_bytes());
+        // packets which cannot be hashed go to the first receive queue
+        data.extend_from_slice(&0u16.to_le_bytes());
+        for &pair in indirection_table {
+            data.extend_from_slice(&pair.to_le_bytes());
+        }
+        // max_tx_vq, only the first transmit queue is used
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.push(key.len() as u8);
+        data.extend_from_slice(key);
+        self.send_ctrl(CTRL_CLASS_MQ, CTRL_MQ_RSS_CONFIG, &data)
+    }
+
+    /// Send a command through the control queue.
+    fn send_ctrl(&mut self, class: u8, command: u8, data: &[u8]) -> Result {
+        let ctrl_queue = self.ctrl_queue.as_mut().ok_or(Error::Unsupported)?;
+        let header = [class, command];
+        let mut ack = [CTRL_ERR];
+        ctrl_queue.submit_and_wait(self.header, &[&header, data], &[&mut ack], usize::MAX)?;
+        match ack[0] {
+            CTRL_OK => Ok(()),
+            _ => Err(Error::IoError),
+        }
+    }
+
     /// Whether can send packet.
     pub fn can_send(&self) -> bool {
         self.send_queue.available_desc() >= 2
@@ -65,29 +228,113 @@ impl VirtIONet<'_> {
 
     /// Whether can receive packet.
     pub fn can_recv(&self) -> bool {
-        self.recv_queue.can_pop()
+        !self.rx_paused && self.recv_queue.can_pop()
+    }
+
+    /// Stop giving receive buffers to the device, so that it holds or drops
+    /// the incoming packets, to apply backpressure.
+    ///
+    /// A receive buffer is only posted while [`VirtIONet::recv`] waits for a
+    /// packet, so there is no buffer left on the receive queue to reclaim.
+    /// The queue itself stays enabled: withholding the buffers is enough for
+    /// the device to stop delivering packets, while disabling a live queue
+    /// would take VIRTIO_F_RING_RESET, which the MMIO transport does not
+    /// implement, and reposting the buffers on resume.
+    pub fn pause_rx(&mut self) {
+        self.rx_paused = true;
+    }
+
+    /// Resume receiving after [`VirtIONet::pause_rx`].
+    pub fn resume_rx(&mut self) {
+        self.rx_paused = false;
     }
 
     /// Receive a packet.
+    ///
+    /// Return [`Error::NotReady`] if receiving is paused.
     pub fn recv(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let mut header = MaybeUninit::<Header>::uninit();
-        let header_buf = unsafe { (*header.as_mut_ptr()).as_buf_mut() };
-        self.recv_queue.add(&[], &[header_buf, buf])?;
-        self.header.notify(QUEUE_RECEIVE as u32);
+        if self.rx_paused {
+            return Err(Error::NotReady);
+        }
+        let mut header = NetHeader::new(self.features);
+        self.recv_queue.add(&[], &[header.as_buf_mut(), buf])?;
+        if self.recv_queue.should_notify() {
+            self.header.notify(QUEUE_RECEIVE as u32);
+        }
         while !self.recv_queue.can_pop() {
             spin_loop();
         }
 
         let (_, len) = self.recv_queue.pop_used()?;
-        // let header = unsafe { header.assume_init() };
-        Ok(len as usize - size_of::<Header>())
+        Ok(len as usize - header.size())
+    }
+
+    /// Receive a packet from the receive queue of `queue_pair`, to which
+    /// [`VirtIONet::configure_rss`] steers packets, e.g. on the core which
+    /// handles the queue pair. The queue pair 0 is the one of
+    /// [`VirtIONet::recv`].
+    ///
+    /// Return [`Error::NotReady`] if receiving is paused, and
+    /// [`Error::InvalidParam`] if the queue pair has not been set up.
+    pub fn recv_from(&mut self, queue_pair: u16, buf: &mut [u8]) -> Result<usize> {
+        if queue_pair == 0 {
+            return self.recv(buf);
+        }
+        if self.rx_paused {
+            return Err(Error::NotReady);
+        }
+        let queue = self
+            .rss_recv_queues
+            .get_mut(queue_pair as usize - 1)
+            .ok_or(Error::InvalidParam)?;
+        let mut header = NetHeader::new(self.features);
+        let len = queue.submit_and_wait(self.header, &[], &[header.as_buf_mut(), buf], usize::MAX)?;
+        Ok(len as usize - header.size())
     }
 
     /// Send a packet.
+    ///
+    /// Return [`Error::InvalidParam`] if the payload of the ethernet frame is
+    /// larger than the MTU of the device.
     pub fn send(&mut self, buf: &[u8]) -> Result {
-        let header = unsafe { MaybeUninit::<Header>::zeroed().assume_init() };
+        if let Some(mtu) = self.mtu {
+            if buf.len() > mtu as usize + ETHERNET_HEADER_SIZE {
+                return Err(Error::InvalidParam);
+            }
+        }
+        self.send_with_header(buf, NetHeader::new(self.features))
+    }
+
+    /// Send a large packet, which the device splits into segments of
+    /// `gso.gso_size` bytes.
+    ///
+    /// Return [`Error::Unsupported`] if the device does not support the
+    /// segmentation offload, or [`Error::InvalidParam`] if `gso.gso_size` is 0.
+    pub fn send_gso(&mut self, buf: &[u8], gso: Gso) -> Result {
+        let supported = match gso.gso_type {
+            GsoType::NONE => return self.send(buf),
+            GsoType::TCPV4 => GsoCaps::TCPV4,
+            GsoType::TCPV6 => GsoCaps::TCPV6,
+            GsoType::UDP => GsoCaps::UDP,
+            // VIRTIO_NET_F_HOST_ECN is not negotiated
+            GsoType::ECN => return Err(Error::Unsupported),
+        };
+        if !self.supported_gso().contains(supported) {
+            return Err(Error::Unsupported);
+        }
+        if gso.gso_size == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let mut header = NetHeader::new(self.features);
+        header.set_gso(&gso);
+        self.send_with_header(buf, header)
+    }
+
+    fn send_with_header(&mut self, buf: &[u8], header: NetHeader) -> Result {
         self.send_queue.add(&[header.as_buf(), buf], &[])?;
-        self.header.notify(QUEUE_TRANSMIT as u32);
+        if self.send_queue.should_notify() {
+            self.header.notify(QUEUE_TRANSMIT as u32);
+        }
         while !self.send_queue.can_pop() {
             spin_loop();
         }
@@ -149,6 +396,8 @@ bitflags! {
         const MQ = 1 << 22;
         /// Set MAC address through control channel.
         const CTL_MAC_ADDR = 1 << 23;
+        /// Device supports receive-side scaling through control channel.
+        const RSS = 1 << 60;
 
         // device independent
         const RING_INDIRECT_DESC = 1 << 28;
@@ -157,6 +406,36 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The segmentation offloads supported by the device.
+    pub struct GsoCaps: u8 {
+        /// TCP over IPv4 segmentation (TSOv4).
+        const TCPV4 = 1 << 0;
+        /// TCP over IPv6 segmentation (TSOv6).
+        const TCPV6 = 1 << 1;
+        /// UDP fragmentation (UFO).
+        const UDP = 1 << 2;
+    }
+}
+
+/// The segmentation offload of a packet to send.
+///
+/// The device computes the checksums of the segments, so the packet is sent
+/// with a partial checksum, as described by `csum_start` and `csum_offset`.
+#[derive(Debug, Clone, Copy)]
+pub struct Gso {
+    /// The type of segmentation.
+    pub gso_type: GsoType,
+    /// The length of the headers to copy into each segment.
+    pub hdr_len: u16,
+    /// The maximum size of the payload of each segment.
+    pub gso_size: u16,
+    /// The offset from which the checksum is computed.
+    pub csum_start: u16,
+    /// The offset after `csum_start` where the checksum is stored.
+    pub csum_offset: u16,
+}
+
 bitflags! {
     struct Status: u16 {
         const LINK_UP = 1;
@@ -176,10 +455,22 @@ bitflags! {
 struct Config {
     mac: ReadOnly<EthernetAddress>,
     status: ReadOnly<Status>,
+    max_virtqueue_pairs: ReadOnly<u16>,
+    mtu: ReadOnly<u16>,
+    speed: ReadOnly<u32>,
+    duplex: ReadOnly<u8>,
+    rss_max_key_size: ReadOnly<u8>,
+    rss_max_indirection_table_length: ReadOnly<u16>,
+    supported_hash_types: ReadOnly<u32>,
 }
 
+unsafe impl ConfigStruct for Config {}
+
 type EthernetAddress = [u8; 6];
 
+/// The size of the header of an ethernet frame, not counted in the MTU.
+const ETHERNET_HEADER_SIZE: usize = 14;
+
 // virtio 5.1.6 Device Operation
 #[repr(C)]
 #[derive(Debug)]
@@ -190,11 +481,93 @@ struct Header {
     gso_size: Volatile<u16>,
     csum_start: Volatile<u16>,
     csum_offset: Volatile<u16>,
+    /// Only present with VIRTIO_NET_F_MRG_RXBUF or VIRTIO_F_VERSION_1.
+    num_buffers: Volatile<u16>,
     // payload starts from here
 }
 
 unsafe impl AsBuf for Header {}
 
+/// The header of a packet, whose size depends on the negotiated features.
+///
+/// Without VIRTIO_NET_F_MRG_RXBUF, the legacy header does not contain the
+/// `num_buffers` field, so it is 10 bytes long instead of 12.
+struct NetHeader {
+    header: Header,
+    size: usize,
+}
+
+impl NetHeader {
+    /// Create a zeroed header for the negotiated features.
+    fn new(features: Features) -> Self {
+        let size = if features.intersects(Features::MRG_RXBUF | Features::VERSION_1) {
+            size_of::<Header>()
+        } else {
+            size_of::<Header>() - size_of::<u16>()
+        };
+        NetHeader {
+            header: unsafe { MaybeUninit::<Header>::zeroed().assume_init() },
+            size,
+        }
+    }
+
+    /// The size of the header in bytes.
+    fn size(&self) -> usize {
+        self.size
+    }
+
+    fn flags(&self) -> Flags {
+        self.header.flags.read()
+    }
+
+    fn gso_type(&self) -> GsoType {
+        self.header.gso_type.read()
+    }
+
+    fn hdr_len(&self) -> u16 {
+        self.header.hdr_len.read()
+    }
+
+    fn gso_size(&self) -> u16 {
+        self.header.gso_size.read()
+    }
+
+    fn csum_start(&self) -> u16 {
+        self.header.csum_start.read()
+    }
+
+    fn csum_offset(&self) -> u16 {
+        self.header.csum_offset.read()
+    }
+
+    /// Fill in the segmentation offload of a packet to send.
+    fn set_gso(&mut self, gso: &Gso) {
+        self.header.flags.write(Flags::NEEDS_CSUM);
+        self.header.gso_type.write(gso.gso_type);
+        self.header.hdr_len.write(gso.hdr_len);
+        self.header.gso_size.write(gso.gso_size);
+        self.header.csum_start.write(gso.csum_start);
+        self.header.csum_offset.write(gso.csum_offset);
+    }
+
+    /// The number of merged buffers, if the header contains the field.
+    fn num_buffers(&self) -> Option<u16> {
+        if self.size == size_of::<Header>() {
+            Some(self.header.num_buffers.read())
+        } else {
+            None
+        }
+    }
+
+    fn as_buf(&self) -> &[u8] {
+        &self.header.as_buf()[..self.size]
+    }
+
+    fn as_buf_mut(&mut self) -> &mut [u8] {
+        &mut self.header.as_buf_mut()[..self.size]
+    }
+}
+
 bitflags! {
     struct Flags: u8 {
         const NEEDS_CSUM = 1;
@@ -203,15 +576,54 @@ bitflags! {
     }
 }
 
+/// The type of segmentation offload of a packet.
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum GsoType {
+pub enum GsoType {
+    /// No segmentation.
     NONE = 0,
+    /// TCP over IPv4.
     TCPV4 = 1,
+    /// UDP.
     UDP = 3,
+    /// TCP over IPv6.
     TCPV6 = 4,
+    /// TCP with ECN.
     ECN = 0x80,
 }
 
 const QUEUE_RECEIVE: usize = 0;
 const QUEUE_TRANSMIT: usize = 1;
+
+/// The size of the receive and transmit queues, small for simplicity.
+const QUEUE_SIZE: usize = 2;
+
+/// The size of the control queue, which holds a single command.
+const CTRL_QUEUE_SIZE: usize = 4;
+
+/// The class of the multiqueue commands of the control queue.
+const CTRL_CLASS_MQ: u8 = 4;
+/// The command setting the RSS configuration.
+const CTRL_MQ_RSS_CONFIG: u8 = 1;
+/// The ack of a command the device has applied.
+const CTRL_OK: u8 = 0;
+/// The ack of a command the device has rejected.
+const CTRL_ERR: u8 = 1;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_layout() {
+        assert_eq!(offset_of!(Config, mac), 0);
+        assert_eq!(offset_of!(Config, status), 6);
+        assert_eq!(offset_of!(Config, max_virtqueue_pairs), 8);
+        assert_eq!(offset_of!(Config, mtu), 10);
+        assert_eq!(offset_of!(Config, speed), 12);
+        assert_eq!(offset_of!(Config, duplex), 16);
+        assert_eq!(offset_of!(Config, rss_max_key_size), 17);
+        assert_eq!(offset_of!(Config, rss_max_indirection_table_length), 18);
+        assert_eq!(offset_of!(Config, supported_hash_types), 20);
+    }
+}
@@ -1,94 +1,2111 @@
 use super::VirtIOPCIHeader;
-use crate::queue::VirtQueue;
+use crate::queue::{DescChainDump, QueueDebugInfo, VirtQueue};
+#[cfg(feature = "stats")]
+use crate::queue::VirtQueueStats;
 use crate::blk::*;
+use crate::header::{describe_transport_features, DeviceType};
 use crate::{Result, AsBuf, Error};
 use log::*;
+#[cfg(feature = "integrity-check")]
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::future::Future;
 use core::hint::spin_loop;
+use core::marker::PhantomData;
+use core::mem::size_of_val;
+use core::pin::Pin;
+use core::slice;
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use core::task::{Context, Poll, Waker};
 
 /// The virtio block device is a simple virtual block device (ie. disk) which is
 /// connected to a PCI bus.
 ///
 /// Read and write requests (and other exotic requests) are placed in the queue,
 /// and serviced (probably out of order) by the device except where noted.
+///
+/// Each request is a chain of the header, the data and the status, in this
+/// order: the device parses the concatenation of the buffers, however they
+/// are split, and the buffers it reads must come before those it writes.
+/// Ref: VirtIO spec v1.1 section 2.6.4 and 5.2.6
+///
+/// A request the device has not completed after a while fails with
+/// [`Error::Timeout`], once the device has been reset so that it no longer
+/// accesses the buffers: the driver has to be created again to use it.
 pub struct VirtIOBlkPCI<'a> {
     header: VirtIOPCIHeader,
-    queue: VirtQueue<'a>,
+    queue: VirtQueue<'a, MAX_QUEUE_SIZE>,
     capacity: usize,
+    /// The number of request queues of the device, only the first is used.
+    num_queues: u16,
+    /// Features negotiated with the device.
+    features: BlkFeature,
+    /// Requests and responses of the prefetches.
+    req_resp: BlkReqResp,
+    /// Outstanding prefetches.
+    prefetches: Vec<Prefetch>,
+    /// Wakers to wake when the request of their token completes.
+    wakers: Vec<(u16, Waker)>,
+    /// The completions of the futures, one slot per pair of the pool.
+    future_slots: Arc<[FutureSlot]>,
+    /// The tokens and pairs of the futures in flight.
+    future_requests: Vec<(u16, usize)>,
+    /// The pairs of the futures, in flight or not collected yet.
+    future_pairs: Vec<usize>,
+    /// Checksums of the blocks written, once the check is enabled.
+    #[cfg(feature = "integrity-check")]
+    checksums: Option<BTreeMap<usize, u32>>,
 }
 
-impl<'a> VirtIOBlkPCI<'a> {
-    /// Create a new VirtIO-Blk PCI driver.
-    pub fn new(mut header: VirtIOPCIHeader) -> Result<Self> {
+/// What [`VirtIOBlkPCI::handle_interrupt`] has found.
+#[derive(Debug, Clone, Default)]
+pub struct InterruptOutcome {
+    /// The tokens of the requests which have completed.
+    pub completed: Vec<u16>,
+    /// Whether the device configuration has changed, e.g. its capacity.
+    pub config_changed: bool,
+}
+
+/// A read submitted by [`VirtIOBlkPCI::prefetch`].
+struct Prefetch {
+    token: u16,
+    block_id: usize,
+    /// Index of the request and response in the pool.
+    req_resp: usize,
+    /// The buffer which the block is read into.
+    buf: *mut u8,
+    /// Whether the device has completed the read.
+    done: bool,
+}
+
+/// A read submitted by [`VirtIOBlkPCI::read_range_streaming`].
+struct StreamRead {
+    token: u16,
+    block_id: usize,
+    blocks: usize,
+    /// Index of the request and response in the pool.
+    req_resp: usize,
+    /// Whether the device has completed the read.
+    done: bool,
+}
+
+/// A request submitted by [`VirtIOBlkPCI::submit_read`] or
+/// [`VirtIOBlkPCI::submit_write`], which borrows its buffer until the device
+/// has completed it.
+///
+/// Dropping it while the request is in flight waits for the completion, so
+/// that the buffer is never freed while the device still accesses it.
+///
+/// It borrows the driver until then, a task should rather await a
+/// [`ReadFuture`] or a [`WriteFuture`], which do not.
+pub struct InflightRequest<'r, 'a> {
+    blk: &'r mut VirtIOBlkPCI<'a>,
+    token: u16,
+    /// Index of the request and response in the pool.
+    req_resp: usize,
+    /// Whether the device has completed the request.
+    done: bool,
+    /// Whether the request and response have been returned to the pool.
+    released: bool,
+    _buf: PhantomData<&'r mut [u8]>,
+}
+
+/// A read of a block submitted by [`VirtIOBlkPCI::read_future`], which
+/// resolves to its status once the device has completed it.
+///
+/// It borrows the buffer but not the driver, so that the interrupt handler
+/// can call [`VirtIOBlkPCI::handle_interrupt`], which records the completion
+/// and wakes the task. The completion is also recorded when another method
+/// of the driver finds it while waiting for its own request.
+///
+/// Dropping it before it resolves pops the used ring of the driver until
+/// the request has completed, so that the buffer is never freed while the
+/// device still accesses it, even if the interrupts are masked or polled.
+pub struct ReadFuture<'r> {
+    request: FutureRequest,
+    _buf: PhantomData<&'r mut [u8]>,
+}
+
+/// A write of a block submitted by [`VirtIOBlkPCI::write_future`], which
+/// resolves to its status once the device has completed it, as a
+/// [`ReadFuture`].
+pub struct WriteFuture<'r> {
+    request: FutureRequest,
+    _buf: PhantomData<&'r [u8]>,
+}
+
+/// The request of a [`ReadFuture`] or a [`WriteFuture`], which collects the
+/// completion from its slot.
+struct FutureRequest {
+    slots: Arc<[FutureSlot]>,
+    /// Index of the slot, which is the index of the pair in the pool.
+    slot: usize,
+    /// Whether the completion has been collected.
+    collected: bool,
+    /// The driver which submitted the request, and the function which pops
+    /// its used ring until the slot has completed, for a request dropped in
+    /// flight.
+    blk: *mut (),
+    wait: unsafe fn(*mut (), usize),
+}
+
+// Safety: the driver is only accessed when the request is dropped in flight,
+// which the caller of `read_future` or `write_future` serialises with the
+// other uses of the driver.
+unsafe impl Send for FutureRequest {}
+
+/// The completion of the request of a future, recorded by the driver.
+///
+/// The waker is guarded by a flag: the driver only tries to take it, so it
+/// never spins in an interrupt handler, and the future checks the state
+/// again once it has registered its waker, so a completion recorded while
+/// the flag is held is not missed.
+struct FutureSlot {
+    /// [`SLOT_FREE`], [`SLOT_IN_FLIGHT`], or the status of the request.
+    state: AtomicU16,
+    /// Whether `waker` is being accessed.
+    locked: AtomicBool,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// Safety: `waker` is only accessed while `locked` is held.
+unsafe impl Sync for FutureSlot {}
+
+/// The state of a slot whose pair can be returned to the pool.
+const SLOT_FREE: u16 = 0x100;
+/// The state of a slot whose request has not completed yet.
+const SLOT_IN_FLIGHT: u16 = 0x101;
+
+/// The outcome of [`VirtIOBlkPCI::read_batch`].
+#[derive(Debug, Clone)]
+pub struct BatchCompletion {
+    /// The sum of the used lengths of the requests.
+    pub total_len: usize,
+    /// The status and used length of each request, in submission order.
+    ///
+    /// The used length of a read counts its data and the status byte.
+    pub requests: Vec<(RespStatus, u32)>,
+}
+
+/// A request submitted in a batch, by [`VirtIOBlkPCI::read_batch`] or by a
+/// split write.
+struct BatchRequest {
+    token: u16,
+    /// Index of the request in the batch.
+    index: usize,
+    /// Index of the request and response in the pool.
+    req_resp: usize,
+}
+
+/// A builder of [`VirtIOBlkPCI`], to choose the queue size and the features.
+pub struct VirtIOBlkPCIBuilder {
+    header: VirtIOPCIHeader,
+    queue_size: u16,
+    features: BlkFeature,
+    dma32: bool,
+}
+
+impl VirtIOBlkPCIBuilder {
+    /// Set the size of the queue, which must be a power of 2 and at most 32.
+    /// The default is 16.
+    pub fn queue_size(mut self, queue_size: u16) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Place the queue in memory below 4GB, for devices which ignore the high
+    /// 32 bits of the queue addresses. The default is false.
+    pub fn dma32(mut self, dma32: bool) -> Self {
+        self.dma32 = dma32;
+        self
+    }
+
+    /// Set the features to request, only those the device offers are
+    /// negotiated. The default is [`BlkFeature::SIZE_MAX`],
+    /// [`BlkFeature::SEG_MAX`], [`BlkFeature::FLUSH`],
+    /// [`BlkFeature::SECURE_ERASE`], [`BlkFeature::ZONED`],
+    /// [`BlkFeature::RING_INDIRECT_DESC`] and [`BlkFeature::IN_ORDER`].
+    ///
+    /// With [`BlkFeature::RING_INDIRECT_DESC`], each request takes a single
+    /// descriptor of the queue, whatever its number of segments.
+    ///
+    /// A host-managed zoned device fails the negotiation without
+    /// [`BlkFeature::ZONED`], and [`VirtIOBlkPCIBuilder::build`] returns
+    /// [`Error::Unsupported`].
+    pub fn request_features(mut self, features: BlkFeature) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Create the VirtIO-Blk PCI driver.
+    ///
+    /// Return [`Error::WrongDeviceType`] if the device is not a block device.
+    pub fn build<'a>(self) -> Result<VirtIOBlkPCI<'a>> {
+        let mut header = self.header;
+        if header.device_type() != DeviceType::Block {
+            return Err(Error::WrongDeviceType);
+        }
+        let supported_features = self.features;
+        let mut negotiated_features = BlkFeature::empty();
         header.begin_init(|features| {
+            debug!("transport features: {}", describe_transport_features(features));
             let features = BlkFeature::from_bits_truncate(features);
-            info!("device features: {:?}", features);
+            debug!("device features: {:?}", features);
             // negotiate these flags only
             // do not use legacy interface, see virtio spec 2.2.3
-            //let supported_features = BlkFeature::VERSION_1;
-            let supported_features = BlkFeature::empty();
-            (features & supported_features).bits()
-        });
+            negotiated_features = features & supported_features;
+            negotiated_features.bits()
+        })?;
 
         // read configuration space
-        let config = unsafe { &mut *(header.config_space() as *mut BlkConfig) };
-        info!("config: {:?}", config);
-        info!(
-            "found a block device of size {}KB",
-            config.capacity.read() / 2
-        );
-
-        let queue = VirtQueue::new_pci(&mut header, 0, 16)?;
+        let config = header.config::<BlkConfig>();
+        debug!("config: {:?}", config);
+        let capacity = config.read_capacity(|| header.config_generation());
+        info!("found a block device of size {}KB", capacity / 2);
+        // the request queues are counted by the block configuration, valid
+        // now that FEATURES_OK is set, not by the common configuration
+        let num_queues = config.num_request_queues(negotiated_features).map_err(|err| {
+            header.set_failed();
+            err
+        })?;
+        debug!("{} request queues", num_queues);
+        if negotiated_features.contains(BlkFeature::ZONED) {
+            info!("zoned device: {:?}", header.config::<BlkConfig>().zone_info());
+        }
+
+        // a table per descriptor of the queue, each as long as a chain may
+        // be, both bounded by the size the device grants
+        let queue = if header.indirect_desc() {
+            let size = self.queue_size;
+            VirtQueue::new_pci_indirect(&mut header, 0, size, self.dma32, size, size)
+        } else {
+            VirtQueue::new_pci(&mut header, 0, self.queue_size, self.dma32)
+        };
+        let resources = queue.and_then(|queue| Ok((queue, BlkReqResp::new()?)));
+        let (queue, req_resp) = resources.map_err(|err| {
+            header.set_failed();
+            err
+        })?;
         header.finish_init();
 
-        Ok(Self {
+        Ok(VirtIOBlkPCI {
             header,
             queue,
-            capacity: config.capacity.read() as usize,
+            capacity: capacity as usize,
+            num_queues,
+            features: negotiated_features,
+            req_resp,
+            prefetches: Vec::new(),
+            wakers: Vec::new(),
+            future_slots: (0..REQ_RESP_POOL_SIZE).map(|_| FutureSlot::new()).collect(),
+            future_requests: Vec::new(),
+            future_pairs: Vec::new(),
+            #[cfg(feature = "integrity-check")]
+            checksums: None,
         })
     }
+}
 
-    /// Acknowledge interrupt.
+impl<'a> VirtIOBlkPCI<'a> {
+    /// Create a new VirtIO-Blk PCI driver.
+    pub fn new(header: VirtIOPCIHeader) -> Result<Self> {
+        Self::builder(header).build()
+    }
+
+    /// Return a builder of the driver, to choose the queue size and the features.
+    pub fn builder(header: VirtIOPCIHeader) -> VirtIOBlkPCIBuilder {
+        VirtIOBlkPCIBuilder {
+            header,
+            queue_size: 16,
+            features: BlkFeature::SIZE_MAX
+                | BlkFeature::SEG_MAX
+                | BlkFeature::FLUSH
+                | BlkFeature::SECURE_ERASE
+                | BlkFeature::ZONED
+                | BlkFeature::RING_INDIRECT_DESC
+                | BlkFeature::IN_ORDER,
+            dma32: false,
+        }
+    }
+
+    /// Acknowledge interrupt and return true if the queue has been updated,
+    /// so that the used ring has to be popped, or false if the interrupt only
+    /// signals a configuration change.
+    ///
+    /// Use [`VirtIOBlkPCI::handle_interrupt`] to also pop the completions
+    /// and learn about the configuration change.
     pub fn ack_interrupt(&mut self) -> bool {
-        unimplemented!()
+        self.header.ack_interrupt().queue
+    }
+
+    /// Handle an interrupt of the device, as an interrupt dispatcher would
+    /// when its IRQ fires.
+    ///
+    /// The ISR status is read once unless MSI-X is used, then the completed
+    /// requests are popped: the prefetches are marked as done, the
+    /// completions of the futures are recorded, and the wakers registered
+    /// for them are woken.
+    pub fn handle_interrupt(&mut self) -> InterruptOutcome {
+        let ack = self.header.ack_interrupt();
+        let mut outcome = InterruptOutcome {
+            config_changed: ack.config,
+            ..InterruptOutcome::default()
+        };
+        if !ack.queue {
+            return outcome;
+        }
+        loop {
+            match self.queue.pop_used() {
+                Ok((token, _)) => {
+                    self.complete_background(token);
+                    if let Some(waker) = self.take_waker(token) {
+                        waker.wake();
+                    }
+                    outcome.completed.push(token);
+                }
+                Err(Error::NotReady) => break,
+                Err(err) => {
+                    warn!("failed to pop a completion: {:?}", err);
+                    break;
+                }
+            }
+        }
+        self.reap_futures();
+        outcome
+    }
+
+    /// Register a waker to wake when the request `token` completes in
+    /// [`VirtIOBlkPCI::handle_interrupt`], replacing the previous one.
+    pub fn register_waker(&mut self, token: u16, waker: &Waker) {
+        match self.wakers.iter_mut().find(|(t, _)| *t == token) {
+            Some((_, registered)) => registered.clone_from(waker),
+            None => self.wakers.push((token, waker.clone())),
+        }
+    }
+
+    /// Remove the waker registered for the request `token`, if any.
+    fn take_waker(&mut self, token: u16) -> Option<Waker> {
+        let idx = self.wakers.iter().position(|(t, _)| *t == token)?;
+        Some(self.wakers.swap_remove(idx).1)
     }
 
     /// Read a block.
+    ///
+    /// If the block has been prefetched and the read has completed, the
+    /// prefetched data is returned without submitting a new request.
     pub fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
         trace!("reading block {:#x}", block_id);
         assert_eq!(buf.len(), BLK_SIZE);
-        let req = BlkReq::new(ReqType::In, 0, block_id as u64);
-        let mut resp = BlkResp::default();
-        //info!("before adding");
-        self.queue.add(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
-        //info!("before notifying");
-        self.header.notify(0);
-        //info!("after notifying");
-        while !self.queue.can_pop() {
-            spin_loop();
-        }
-        self.queue.pop_used()?;
-        //info!("poped!");
-        match resp.status() {
-            RespStatus::Ok => Ok(()),
-            _ => Err(Error::IoError),
-        }
+        let result = match self.read_prefetched(block_id, buf) {
+            Some(result) => result,
+            None => {
+                let req = BlkReq::new(ReqType::In, 0, block_id as u64);
+                let mut resp = StackResp::default();
+                let token = self.queue.add(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
+                trace!("added request {}, notifying", token);
+                self.notify();
+                self.wait_for(token)?;
+                trace!("request {} completed", token);
+                resp_to_result(&resp)
+            }
+        };
+        result?;
+        #[cfg(feature = "integrity-check")]
+        self.verify_checksums(block_id, buf)?;
+        Ok(())
     }
 
     /// Write a block.
     pub fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        trace!("writing block {:#x}", block_id);
         assert_eq!(buf.len(), BLK_SIZE);
+        // a prefetch of this block would return stale data
+        self.discard_prefetch(block_id)?;
         let req = BlkReq::new(ReqType::Out, 0, block_id as u64);
-        let mut resp = BlkResp::default();
-        self.queue.add(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
-        self.header.notify(0);
-        while !self.queue.can_pop() {
+        let mut resp = StackResp::default();
+        let token = self.queue.add(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
+        self.notify();
+        self.wait_for(token)?;
+        resp_to_result(&resp)?;
+        #[cfg(feature = "integrity-check")]
+        self.record_checksums(block_id, buf);
+        Ok(())
+    }
+
+    /// Read consecutive blocks from `block_id` into `buf`, whose length is a
+    /// multiple of the block size.
+    ///
+    /// If `buf` exceeds the maximum segment size, the range is split into a
+    /// batch of requests within it, and the first error is returned.
+    /// Return [`Error::InvalidParam`] if the length of `buf` is not a multiple
+    /// of the block size, or if the blocks are beyond the capacity.
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        if buf.len() % BLK_SIZE != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let (req, len) =
+            BlkReq::for_range(ReqType::In, block_id as u64, buf.len() / BLK_SIZE, self.capacity as u64)?;
+        debug_assert_eq!(len, buf.len());
+        let max_len = self.max_request_len();
+        self.check_segments(1, len.min(max_len))?;
+        trace!("reading {} blocks from {:#x}", len / BLK_SIZE, block_id);
+        if len > max_len {
+            return self.read_split(block_id, buf, max_len);
+        }
+        let mut resp = StackResp::default();
+        self.submit_and_wait(&[req.as_buf()], &[buf, resp.as_buf_mut()])?;
+        resp_to_result(&resp)?;
+        #[cfg(feature = "integrity-check")]
+        self.verify_checksums(block_id, buf)?;
+        Ok(())
+    }
+
+    /// Write consecutive blocks from `block_id` from `buf`, whose length is a
+    /// multiple of the block size.
+    ///
+    /// The range is split as by [`VirtIOBlkPCI::read_blocks`].
+    /// Return [`Error::InvalidParam`] as [`VirtIOBlkPCI::read_blocks`].
+    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        if buf.len() % BLK_SIZE != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let (req, len) =
+            BlkReq::for_range(ReqType::Out, block_id as u64, buf.len() / BLK_SIZE, self.capacity as u64)?;
+        debug_assert_eq!(len, buf.len());
+        let max_len = self.max_request_len();
+        self.check_segments(1, len.min(max_len))?;
+        trace!("writing {} blocks from {:#x}", len / BLK_SIZE, block_id);
+        // prefetches of these blocks would return stale data
+        for block in block_id..block_id + len / BLK_SIZE {
+            self.discard_prefetch(block)?;
+        }
+        if len > max_len {
+            self.write_split(block_id, buf, max_len)?;
+        } else {
+            let mut resp = StackResp::default();
+            self.submit_and_wait(&[req.as_buf(), buf], &[resp.as_buf_mut()])?;
+            resp_to_result(&resp)?;
+        }
+        #[cfg(feature = "integrity-check")]
+        self.record_checksums(block_id, buf);
+        Ok(())
+    }
+
+    /// The maximum length of the data of a request with a single segment, in
+    /// whole blocks, as limited by VIRTIO_BLK_F_SIZE_MAX.
+    fn max_request_len(&self) -> usize {
+        if !self.features.contains(BlkFeature::SIZE_MAX) {
+            return usize::MAX;
+        }
+        let size_max = self.header.config::<BlkConfig>().size_max.read() as usize;
+        // a single block is rejected by `check_segments` if it is too large
+        (size_max / BLK_SIZE).max(1) * BLK_SIZE
+    }
+
+    /// Read a range larger than `max_len` bytes as a batch of requests of at
+    /// most `max_len` bytes, return the first error.
+    fn read_split(&mut self, block_id: usize, buf: &mut [u8], max_len: usize) -> Result {
+        let blocks = max_len / BLK_SIZE;
+        let mut reads: Vec<_> = buf
+            .chunks_mut(max_len)
+            .enumerate()
+            .map(|(index, chunk)| (block_id + index * blocks, chunk))
+            .collect();
+        let completion = self.read_batch(&mut reads)?;
+        completion.requests.iter().try_for_each(|&(status, _)| status_to_result(status))
+    }
+
+    /// Write a range larger than `max_len` bytes as a batch of requests of at
+    /// most `max_len` bytes, notifying the device once for all of them as
+    /// [`VirtIOBlkPCI::read_batch`], return the first error.
+    fn write_split(&mut self, block_id: usize, buf: &[u8], max_len: usize) -> Result {
+        let blocks = max_len / BLK_SIZE;
+        let mut pending = Vec::new();
+        let mut requests = Vec::new();
+        requests.resize(buf.chunks(max_len).len(), (RespStatus::_NotReady, 0));
+        let mut result = Ok(());
+        'submit: for (index, chunk) in buf.chunks(max_len).enumerate() {
+            loop {
+                match self.submit_batch_write(block_id + index * blocks, chunk) {
+                    Ok((token, req_resp)) => {
+                        pending.push(BatchRequest { token, index, req_resp });
+                        break;
+                    }
+                    Err(Error::QueueFull) if !pending.is_empty() => {
+                        self.notify();
+                        self.complete_batch_request(&mut pending, &mut requests)?;
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        break 'submit;
+                    }
+                }
+            }
+        }
+        self.notify();
+        while !pending.is_empty() {
+            self.complete_batch_request(&mut pending, &mut requests)?;
+        }
+        result?;
+        requests.iter().try_for_each(|&(status, _)| status_to_result(status))
+    }
+
+    /// Check that `count` data segments of at most `max_len` bytes fit in the
+    /// queue and in the limits of the device.
+    fn check_segments(&self, count: usize, max_len: usize) -> Result {
+        // the request header and the status take a descriptor each
+        if count == 0 || count + 2 > self.queue.size() as usize {
+            return Err(Error::InvalidParam);
+        }
+        let config = self.header.config::<BlkConfig>();
+        if self.features.contains(BlkFeature::SEG_MAX) && count > config.seg_max.read() as usize {
+            return Err(Error::InvalidParam);
+        }
+        if self.features.contains(BlkFeature::SIZE_MAX) && max_len > config.size_max.read() as usize {
+            return Err(Error::InvalidParam);
+        }
+        Ok(())
+    }
+
+    /// Write consecutive blocks from `block_id` from several buffers, each of
+    /// them being a segment of the request, without copying them.
+    ///
+    /// Return [`Error::InvalidParam`] if the total size is not a multiple of
+    /// the block size, if the blocks are beyond the capacity, or if the
+    /// segments exceed the limits of the device or of the queue.
+    pub fn write_block_sg(&mut self, block_id: usize, segments: &[&[u8]]) -> Result {
+        let total: usize = segments.iter().map(|segment| segment.len()).sum();
+        if total % BLK_SIZE != 0 {
+            return Err(Error::InvalidParam);
+        }
+        let max_len = segments.iter().map(|segment| segment.len()).max().unwrap_or(0);
+        self.check_segments(segments.len(), max_len)?;
+        let (req, _) =
+            BlkReq::for_range(ReqType::Out, block_id as u64, total / BLK_SIZE, self.capacity as u64)?;
+        trace!("writing {} blocks from {:#x}", total / BLK_SIZE, block_id);
+        // prefetches of these blocks would return stale data
+        for block in block_id..block_id + total / BLK_SIZE {
+            self.discard_prefetch(block)?;
+        }
+        // the blocks may span several segments, so they are not checked
+        #[cfg(feature = "integrity-check")]
+        self.forget_checksums(block_id, total / BLK_SIZE);
+        let mut inputs = Vec::with_capacity(segments.len() + 1);
+        inputs.push(req.as_buf());
+        inputs.extend_from_slice(segments);
+        let mut resp = StackResp::default();
+        self.submit_and_wait(&inputs, &[resp.as_buf_mut()])?;
+        resp_to_result(&resp)
+    }
+
+    /// Read `nblocks` blocks from `start_block` through `chunk`, whose length
+    /// is a multiple of the block size, calling `on_chunk` with the first
+    /// block id and the data of each piece as it completes.
+    ///
+    /// The chunk is split in two halves, so that the device reads into one of
+    /// them while the other is processed.
+    /// Return [`Error::InvalidParam`] if the length of `chunk` is not a
+    /// nonzero multiple of the block size, if the blocks are beyond the
+    /// capacity, or if half of `chunk` exceeds the maximum segment size.
+    pub fn read_range_streaming(
+        &mut self,
+        start_block: usize,
+        nblocks: usize,
+        chunk: &mut [u8],
+        mut on_chunk: impl FnMut(usize, &[u8]),
+    ) -> Result {
+        if chunk.is_empty() || chunk.len() % BLK_SIZE != 0 {
+            return Err(Error::InvalidParam);
+        }
+        BlkReq::for_range(ReqType::In, start_block as u64, nblocks, self.capacity as u64)?;
+        let piece_len = (chunk.len() / BLK_SIZE + 1) / 2 * BLK_SIZE;
+        self.check_segments(1, piece_len)?;
+        let pieces: Vec<(*mut u8, usize)> = chunk
+            .chunks_mut(piece_len)
+            .map(|piece| (piece.as_mut_ptr(), piece.len() / BLK_SIZE))
+            .collect();
+        let end = start_block + nblocks;
+        let mut next = start_block;
+        let mut reads: [Option<StreamRead>; 2] = [None, None];
+        let mut result = Ok(());
+        for (slot, &(buf, blocks)) in pieces.iter().enumerate() {
+            if next == end {
+                break;
+            }
+            match self.submit_stream_read(next, buf, blocks.min(end - next)) {
+                Ok(read) => {
+                    next += read.blocks;
+                    reads[slot] = Some(read);
+                }
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        let mut slot = 0;
+        while result.is_ok() {
+            if reads[slot].is_none() {
+                break;
+            }
+            result = self.wait_stream_read(&mut reads, slot);
+            if result.is_err() {
+                break;
+            }
+            let read = reads[slot].take().unwrap();
+            result = status_to_result(self.req_resp.resp(read.req_resp).status());
+            self.req_resp.release(read.req_resp);
+            if result.is_err() {
+                break;
+            }
+            let (buf, blocks) = pieces[slot];
+            // Safety: the device has completed the read into the piece, which
+            // is part of `chunk`.
+            let data = unsafe { slice::from_raw_parts(buf, read.blocks * BLK_SIZE) };
+            #[cfg(feature = "integrity-check")]
+            {
+                result = self.verify_checksums(read.block_id, data);
+                if result.is_err() {
+                    break;
+                }
+            }
+            on_chunk(read.block_id, data);
+            if next < end {
+                match self.submit_stream_read(next, buf, blocks.min(end - next)) {
+                    Ok(read) => {
+                        next += read.blocks;
+                        reads[slot] = Some(read);
+                    }
+                    Err(err) => result = Err(err),
+                }
+            }
+            slot = (slot + 1) % pieces.len();
+        }
+        // the device must be done with `chunk` before returning
+        for slot in 0..reads.len() {
+            self.wait_stream_read(&mut reads, slot)?;
+            if let Some(read) = reads[slot].take() {
+                self.req_resp.release(read.req_resp);
+            }
+        }
+        result
+    }
+
+    /// Submit the read of `blocks` blocks from `block_id` into `buf`.
+    fn submit_stream_read(&mut self, block_id: usize, buf: *mut u8, blocks: usize) -> Result<StreamRead> {
+        let req_resp = self.req_resp.acquire(ReqType::In, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        // Safety: `buf` is a piece of the chunk of the caller, which is not
+        // accessed until the read completes.
+        let data = unsafe { slice::from_raw_parts_mut(buf, blocks * BLK_SIZE) };
+        let token = match self.queue.add(&[req], &[data, resp]) {
+            Ok(token) => token,
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                return Err(err);
+            }
+        };
+        self.notify();
+        Ok(StreamRead {
+            token,
+            block_id,
+            blocks,
+            req_resp,
+            done: false,
+        })
+    }
+
+    /// Read a batch of ranges, each given by its first block id and a buffer
+    /// whose length is a multiple of the block size, notifying the device
+    /// once for all of them.
+    ///
+    /// The used lengths are summed so that a short read can be detected and
+    /// found in the returned statuses. If the queue fills up, the reads
+    /// already submitted are waited for to make room.
+    /// Return [`Error::InvalidParam`] as [`VirtIOBlkPCI::read_blocks`].
+    pub fn read_batch(&mut self, reads: &mut [(usize, &mut [u8])]) -> Result<BatchCompletion> {
+        for (block_id, buf) in reads.iter() {
+            if buf.len() % BLK_SIZE != 0 {
+                return Err(Error::InvalidParam);
+            }
+            BlkReq::for_range(ReqType::In, *block_id as u64, buf.len() / BLK_SIZE, self.capacity as u64)?;
+            self.check_segments(1, buf.len())?;
+        }
+        let mut pending = Vec::new();
+        let mut requests = Vec::new();
+        requests.resize(reads.len(), (RespStatus::_NotReady, 0));
+        let mut result = Ok(());
+        'submit: for (index, (block_id, buf)) in reads.iter_mut().enumerate() {
+            loop {
+                match self.submit_batch_read(*block_id, buf) {
+                    Ok((token, req_resp)) => {
+                        pending.push(BatchRequest { token, index, req_resp });
+                        break;
+                    }
+                    Err(Error::QueueFull) if !pending.is_empty() => {
+                        self.notify();
+                        self.complete_batch_request(&mut pending, &mut requests)?;
+                    }
+                    Err(err) => {
+                        result = Err(err);
+                        break 'submit;
+                    }
+                }
+            }
+        }
+        self.notify();
+        while !pending.is_empty() {
+            self.complete_batch_request(&mut pending, &mut requests)?;
+        }
+        result?;
+        #[cfg(feature = "integrity-check")]
+        for ((block_id, buf), &(status, _)) in reads.iter().zip(requests.iter()) {
+            if status == RespStatus::Ok {
+                self.verify_checksums(*block_id, buf)?;
+            }
+        }
+        let total_len = requests.iter().map(|&(_, len)| len as usize).sum();
+        Ok(BatchCompletion { total_len, requests })
+    }
+
+    /// Add the read of `buf` from `block_id` to the queue without notifying
+    /// the device, return its token and its index in the pool.
+    fn submit_batch_read(&mut self, block_id: usize, buf: &mut [u8]) -> Result<(u16, usize)> {
+        let req_resp = self.req_resp.acquire(ReqType::In, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        match self.queue.add(&[req], &[buf, resp]) {
+            Ok(token) => Ok((token, req_resp)),
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Add the write of `buf` to `block_id` to the queue without notifying
+    /// the device, return its token and its index in the pool.
+    fn submit_batch_write(&mut self, block_id: usize, buf: &[u8]) -> Result<(u16, usize)> {
+        let req_resp = self.req_resp.acquire(ReqType::Out, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        match self.queue.add(&[req, buf], &[resp]) {
+            Ok(token) => Ok((token, req_resp)),
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Wait for one of the `pending` requests to complete and record its
+    /// status and used length, recording the completed prefetches on the way.
+    fn complete_batch_request(
+        &mut self,
+        pending: &mut Vec<BatchRequest>,
+        requests: &mut [(RespStatus, u32)],
+    ) -> Result {
+        loop {
+            let mut spins = 0usize;
+            while self.queue.used_count() == 0 {
+                spins += 1;
+                if spins == STALL_SPINS {
+                    return Err(self.give_up());
+                }
+                spin_loop();
+            }
+            let (used, len) = self.queue.pop_used()?;
+            if let Some(idx) = pending.iter().position(|request| request.token == used) {
+                let request = pending.swap_remove(idx);
+                requests[request.index] = (self.req_resp.resp(request.req_resp).status(), len);
+                self.req_resp.release(request.req_resp);
+                return Ok(());
+            }
+            self.complete_background(used);
+        }
+    }
+
+    /// Securely erase `count` blocks from `start_block`.
+    ///
+    /// Return [`Error::Unsupported`] if VIRTIO_BLK_F_SECURE_ERASE is not
+    /// negotiated, or [`Error::InvalidParam`] if the range exceeds the maximum
+    /// or is not aligned as the device requires.
+    pub fn secure_erase(&mut self, start_block: usize, count: usize) -> Result {
+        if !self.features.contains(BlkFeature::SECURE_ERASE) {
+            return Err(Error::Unsupported);
+        }
+        let config = self.header.config::<BlkConfig>();
+        let max_sectors = config.max_secure_erase_sectors.read() as usize;
+        let alignment = config.secure_erase_sector_alignment.read().max(1) as usize;
+        if count == 0 || count > max_sectors || start_block % alignment != 0 || count % alignment != 0 {
+            return Err(Error::InvalidParam);
+        }
+        // a prefetch of the erased blocks would return stale data
+        self.discard_prefetches(start_block, count)?;
+        let req = BlkReq::new(ReqType::SecureErase, 0, 0);
+        let range = BlkDiscardWriteZeroes::new(start_block as u64, count as u32, 0);
+        #[cfg(feature = "integrity-check")]
+        self.forget_checksums(start_block, count);
+        let mut resp = StackResp::default();
+        self.submit_and_wait(&[req.as_buf(), range.as_buf()], &[resp.as_buf_mut()])?;
+        resp_to_result(&resp)
+    }
+
+    /// Flush the write cache of the device, so that the completed writes are
+    /// durable.
+    ///
+    /// Return [`Error::Unsupported`] if [`BlkFeature::FLUSH`] is not
+    /// negotiated.
+    pub fn flush(&mut self) -> Result {
+        if !self.features.contains(BlkFeature::FLUSH) {
+            return Err(Error::Unsupported);
+        }
+        let (status, _) = self.raw_request(ReqType::Flush as u32, 0, RequestData::None)?;
+        status_to_result(status)
+    }
+
+    /// Record a checksum of every block written from now on, and check the
+    /// blocks read back against it, failing the read with
+    /// [`Error::IntegrityCheckFailed`] on a mismatch.
+    ///
+    /// Blocks not written through this driver since, including those written
+    /// by [`VirtIOBlkPCI::raw_request`], are not checked.
+    #[cfg(feature = "integrity-check")]
+    pub fn enable_integrity_check(&mut self) {
+        if self.checksums.is_none() {
+            self.checksums = Some(BTreeMap::new());
+        }
+    }
+
+    /// Read the zone characteristics, or `None` if [`BlkFeature::ZONED`] has
+    /// not been negotiated.
+    pub fn zone_info(&self) -> Option<ZoneInfo> {
+        if !self.features.contains(BlkFeature::ZONED) {
+            return None;
+        }
+        Some(self.header.config::<BlkConfig>().zone_info())
+    }
+
+    /// Report the zones from the one containing the sector `start` into
+    /// `zones`, return the number of zones reported, at most its length.
+    ///
+    /// Return [`Error::Unsupported`] if [`BlkFeature::ZONED`] has not been
+    /// negotiated.
+    pub fn report_zones(&mut self, start: u64, zones: &mut [BlkZoneDescriptor]) -> Result<usize> {
+        self.check_zoned()?;
+        if zones.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let req = BlkReq::new(ReqType::ZoneReport, 0, start);
+        let mut report = BlkZoneReport::default();
+        let mut status = [RespStatus::_NotReady as u8];
+        // Safety: the descriptors are plain data, whatever the device writes
+        // into them is valid.
+        let buf = unsafe { slice::from_raw_parts_mut(zones.as_mut_ptr() as *mut u8, size_of_val(zones)) };
+        self.submit_and_wait(&[req.as_buf()], &[report.as_buf_mut(), buf, &mut status])?;
+        zone_status_to_result(status[0])?;
+        Ok((report.nr_zones as usize).min(zones.len()))
+    }
+
+    /// Open the zone starting at the sector `zone_start` explicitly.
+    ///
+    /// Return [`Error::Unsupported`] if [`BlkFeature::ZONED`] has not been
+    /// negotiated, and [`Error::ZoneLimitReached`] if too many zones are
+    /// open or active.
+    pub fn open_zone(&mut self, zone_start: u64) -> Result {
+        self.manage_zone(ReqType::ZoneOpen, zone_start)
+    }
+
+    /// Close the zone starting at the sector `zone_start`.
+    ///
+    /// Return [`Error::Unsupported`] if [`BlkFeature::ZONED`] has not been
+    /// negotiated.
+    pub fn close_zone(&mut self, zone_start: u64) -> Result {
+        self.manage_zone(ReqType::ZoneClose, zone_start)
+    }
+
+    /// Make the zone starting at the sector `zone_start` full, so that it is
+    /// no longer written.
+    ///
+    /// Return [`Error::Unsupported`] if [`BlkFeature::ZONED`] has not been
+    /// negotiated.
+    pub fn finish_zone(&mut self, zone_start: u64) -> Result {
+        self.manage_zone(ReqType::ZoneFinish, zone_start)
+    }
+
+    /// Reset the write pointer of the zone starting at the sector
+    /// `zone_start`, discarding its data.
+    ///
+    /// Return [`Error::Unsupported`] if [`BlkFeature::ZONED`] has not been
+    /// negotiated.
+    pub fn reset_zone(&mut self, zone_start: u64) -> Result {
+        self.check_zoned()?;
+        let zone_sectors = self.header.config::<BlkConfig>().zone_sectors.read() as usize;
+        // prefetches of the zone would return its old data
+        self.discard_prefetches(zone_start as usize, zone_sectors)?;
+        #[cfg(feature = "integrity-check")]
+        self.forget_checksums(zone_start as usize, zone_sectors);
+        self.manage_zone(ReqType::ZoneReset, zone_start)
+    }
+
+    /// Append `data` to the zone starting at the sector `zone_start`, at its
+    /// write pointer, return the sector the data has been written at.
+    ///
+    /// The length of `data` must be a multiple of the block size, and at most
+    /// the maximum of a zone append request, or [`Error::InvalidParam`] is
+    /// returned. Return [`Error::Unsupported`] if [`BlkFeature::ZONED`] has
+    /// not been negotiated.
+    pub fn zone_append(&mut self, zone_start: u64, data: &[u8]) -> Result<u64> {
+        self.check_zoned()?;
+        let max_len = self.header.config::<BlkConfig>().max_append_sectors.read() as usize * BLK_SIZE;
+        if data.is_empty() || data.len() % BLK_SIZE != 0 || data.len() > max_len {
+            return Err(Error::InvalidParam);
+        }
+        let req = BlkReq::new(ReqType::ZoneAppend, 0, zone_start);
+        // the device writes the sector the data is appended at, then the
+        // status, the last byte of the buffer
+        let mut resp = [0u8; 9];
+        resp[8] = RespStatus::_NotReady as u8;
+        self.submit_and_wait(&[req.as_buf(), data], &[&mut resp])?;
+        zone_status_to_result(resp[8])?;
+        let mut sector = [0u8; 8];
+        sector.copy_from_slice(&resp[..8]);
+        let sector = u64::from_le_bytes(sector);
+        // prefetches of these blocks would return stale data
+        self.discard_prefetches(sector as usize, data.len() / BLK_SIZE)?;
+        #[cfg(feature = "integrity-check")]
+        self.record_checksums(sector as usize, data);
+        Ok(sector)
+    }
+
+    /// Issue a zone management request on the zone starting at `zone_start`.
+    fn manage_zone(&mut self, type_: ReqType, zone_start: u64) -> Result {
+        self.check_zoned()?;
+        let req = BlkReq::new(type_, 0, zone_start);
+        let mut status = [RespStatus::_NotReady as u8];
+        self.submit_and_wait(&[req.as_buf()], &[&mut status])?;
+        zone_status_to_result(status[0])
+    }
+
+    fn check_zoned(&self) -> Result {
+        if self.features.contains(BlkFeature::ZONED) {
+            Ok(())
+        } else {
+            Err(Error::Unsupported)
+        }
+    }
+
+    /// Issue a request of any type, including those not modeled by [`ReqType`].
+    ///
+    /// Return the status byte and the length the device reports as used,
+    /// which includes the status byte.
+    ///
+    /// The prefetches of the blocks a write changes are discarded first, and
+    /// all of them for a type which is not known to only read.
+    pub fn raw_request(
+        &mut self,
+        req_type: u32,
+        sector: u64,
+        data: RequestData,
+    ) -> Result<(RespStatus, u32)> {
+        match (req_type, &data) {
+            (t, RequestData::Out(buf)) if t == ReqType::Out as u32 => {
+                let count = (buf.len() + BLK_SIZE - 1) / BLK_SIZE;
+                self.discard_prefetches(sector as usize, count)?;
+            }
+            (t, _) if t == ReqType::In as u32 || t == ReqType::Flush as u32 || t == ReqType::ZoneReport as u32 => {}
+            _ => self.discard_prefetches(0, self.capacity)?,
+        }
+        let req = BlkReq::raw(req_type, 0, sector);
+        let mut status = [RespStatus::_NotReady as u8];
+        let len = match data {
+            RequestData::In(buf) => self.submit_and_wait(&[req.as_buf()], &[buf, &mut status])?,
+            RequestData::Out(buf) => self.submit_and_wait(&[req.as_buf(), buf], &[&mut status])?,
+            RequestData::None => self.submit_and_wait(&[req.as_buf()], &[&mut status])?,
+        };
+        Ok((RespStatus::from(status[0]), len))
+    }
+
+    /// Read a block, retrying up to `retries` times if the device reports an
+    /// I/O error. Other errors, such as an unsupported request, fail immediately.
+    pub fn read_block_retry(&mut self, block_id: usize, buf: &mut [u8], retries: u8) -> Result {
+        let mut tries = 0;
+        loop {
+            match self.read_block(block_id, buf) {
+                Err(Error::IoError) if tries < retries => {
+                    warn!("I/O error when reading block {:#x}, retrying", block_id);
+                    tries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Write a block, retrying up to `retries` times if the device reports an
+    /// I/O error. Other errors, such as an unsupported request, fail immediately.
+    ///
+    /// Retrying is only safe because the device does not partially write the
+    /// block when it reports an I/O error.
+    pub fn write_block_retry(&mut self, block_id: usize, buf: &[u8], retries: u8) -> Result {
+        let mut tries = 0;
+        loop {
+            match self.write_block(block_id, buf) {
+                Err(Error::IoError) if tries < retries => {
+                    warn!("I/O error when writing block {:#x}, retrying", block_id);
+                    tries += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Submit a read of a block without waiting for it, so that a following
+    /// [`VirtIOBlkPCI::read_block`] of the same block can return immediately.
+    ///
+    /// # Safety
+    ///
+    /// `into` is still borrowed by the underlying virtio block device even if this
+    /// method returns. Thus, it is the caller's responsibility to guarantee that
+    /// `into` is not accessed until the block is read through
+    /// [`VirtIOBlkPCI::read_block`] or written through [`VirtIOBlkPCI::write_block`].
+    pub unsafe fn prefetch(&mut self, block_id: usize, into: &mut [u8]) -> Result {
+        assert_eq!(into.len(), BLK_SIZE);
+        if self.prefetches.iter().any(|p| p.block_id == block_id) {
+            return Ok(());
+        }
+        let req_resp = self.req_resp.acquire(ReqType::In, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        let buf = into.as_mut_ptr();
+        let token = match self.queue.add(&[req], &[into, resp]) {
+            Ok(token) => token,
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                return Err(err);
+            }
+        };
+        self.notify();
+        self.prefetches.push(Prefetch {
+            token,
+            block_id,
+            req_resp,
+            buf,
+            done: false,
+        });
+        Ok(())
+    }
+
+    /// The number of request queues of the device, from its block
+    /// configuration if [`BlkFeature::MQ`] has been negotiated. The driver
+    /// only uses the first one.
+    pub fn num_request_queues(&self) -> u16 {
+        self.num_queues
+    }
+
+    /// The number of simple requests, made of a header, a data buffer and a
+    /// status, which can be outstanding at the same time.
+    ///
+    /// Each of them takes 3 descriptors of the queue, or a single one and an
+    /// indirect table with [`BlkFeature::RING_INDIRECT_DESC`], and the
+    /// non-blocking ones also a request and response pair of the pool.
+    pub fn max_inflight(&self) -> usize {
+        self.queue.max_chains(3).min(REQ_RESP_POOL_SIZE)
+    }
+
+    /// Submit a read of a block into `buf` without waiting for it.
+    ///
+    /// The returned request borrows `buf` until it has completed.
+    ///
+    /// # Safety
+    ///
+    /// The device writes `buf` until the request has completed, which only
+    /// dropping the returned request waits for. Thus, the caller must not leak
+    /// it, with [`core::mem::forget`] or otherwise, as the borrow of `buf`
+    /// would end while the device still accesses it.
+    pub unsafe fn submit_read<'r>(
+        &'r mut self,
+        block_id: usize,
+        buf: &'r mut [u8],
+    ) -> Result<InflightRequest<'r, 'a>> {
+        assert_eq!(buf.len(), BLK_SIZE);
+        let req_resp = self.req_resp.acquire(ReqType::In, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        match self.queue.add(&[req], &[buf, resp]) {
+            Ok(token) => Ok(self.inflight(token, req_resp)),
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Submit a write of a block from `buf` without waiting for it.
+    ///
+    /// The returned request borrows `buf` until it has completed.
+    ///
+    /// # Safety
+    ///
+    /// The device reads `buf` until the request has completed, so the caller
+    /// must not leak the returned request, as by
+    /// [`VirtIOBlkPCI::submit_read`].
+    pub unsafe fn submit_write<'r>(
+        &'r mut self,
+        block_id: usize,
+        buf: &'r [u8],
+    ) -> Result<InflightRequest<'r, 'a>> {
+        assert_eq!(buf.len(), BLK_SIZE);
+        // a prefetch of this block would return stale data
+        self.discard_prefetch(block_id)?;
+        // asynchronous writes are not recorded, forget the old data
+        #[cfg(feature = "integrity-check")]
+        self.forget_checksums(block_id, 1);
+        let req_resp = self.req_resp.acquire(ReqType::Out, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        match self.queue.add(&[req, buf], &[resp]) {
+            Ok(token) => Ok(self.inflight(token, req_resp)),
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Submit a read of a block into `buf`, return a future which resolves
+    /// once the device has completed it, and which only borrows `buf`.
+    ///
+    /// # Safety
+    ///
+    /// The device writes `buf` until the request has completed, which only
+    /// the future waits for. Thus, the caller must not leak the future. Until
+    /// it has resolved, the driver must not be moved or dropped, and dropping
+    /// the future, which then pops the used ring of the driver, must not
+    /// happen while the driver is in use, e.g. by the interrupt handler.
+    pub unsafe fn read_future<'r>(&mut self, block_id: usize, buf: &'r mut [u8]) -> Result<ReadFuture<'r>> {
+        assert_eq!(buf.len(), BLK_SIZE);
+        self.reap_futures();
+        let req_resp = self.req_resp.acquire(ReqType::In, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        match self.queue.add(&[req], &[buf, resp]) {
+            Ok(token) => Ok(ReadFuture {
+                request: self.future_request(token, req_resp),
+                _buf: PhantomData,
+            }),
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Submit a write of a block from `buf`, return a future which resolves
+    /// once the device has completed it, and which only borrows `buf`.
+    ///
+    /// # Safety
+    ///
+    /// The device reads `buf` until the request has completed, so the caller
+    /// must uphold the contract of [`VirtIOBlkPCI::read_future`].
+    pub unsafe fn write_future<'r>(&mut self, block_id: usize, buf: &'r [u8]) -> Result<WriteFuture<'r>> {
+        assert_eq!(buf.len(), BLK_SIZE);
+        // a prefetch of this block would return stale data
+        self.discard_prefetch(block_id)?;
+        // asynchronous writes are not recorded, forget the old data
+        #[cfg(feature = "integrity-check")]
+        self.forget_checksums(block_id, 1);
+        self.reap_futures();
+        let req_resp = self.req_resp.acquire(ReqType::Out, block_id as u64)?;
+        let (req, resp) = self.req_resp.buffers(req_resp);
+        match self.queue.add(&[req, buf], &[resp]) {
+            Ok(token) => Ok(WriteFuture {
+                request: self.future_request(token, req_resp),
+                _buf: PhantomData,
+            }),
+            Err(err) => {
+                self.req_resp.release(req_resp);
+                Err(err)
+            }
+        }
+    }
+
+    /// Read a block asynchronously, with [`VirtIOBlkPCI::read_future`].
+    ///
+    /// The read is submitted before the returned future is first polled, so
+    /// that the future only borrows `buf`, not the driver. The task is woken
+    /// by [`VirtIOBlkPCI::handle_interrupt`], so the interrupt handler of
+    /// the device must call it.
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be handled as the future of
+    /// [`VirtIOBlkPCI::read_future`].
+    pub unsafe fn read_block_async<'r>(&mut self, block_id: usize, buf: &'r mut [u8]) -> impl Future<Output = Result> + 'r {
+        let future = self.read_future(block_id, buf);
+        async move { future?.await }
+    }
+
+    /// Write a block asynchronously, with [`VirtIOBlkPCI::write_future`].
+    ///
+    /// The write is submitted and the task is woken as by
+    /// [`VirtIOBlkPCI::read_block_async`].
+    ///
+    /// # Safety
+    ///
+    /// The returned future must be handled as the future of
+    /// [`VirtIOBlkPCI::write_future`].
+    pub unsafe fn write_block_async<'r>(&mut self, block_id: usize, buf: &'r [u8]) -> impl Future<Output = Result> + 'r {
+        let future = self.write_future(block_id, buf);
+        async move { future?.await }
+    }
+
+    /// Notify the device of the request `token` of a future, whose response
+    /// is in the pair `req_resp`.
+    fn future_request(&mut self, token: u16, req_resp: usize) -> FutureRequest {
+        self.future_slots[req_resp].state.store(SLOT_IN_FLIGHT, Ordering::Release);
+        self.future_requests.push((token, req_resp));
+        self.future_pairs.push(req_resp);
+        self.notify();
+        FutureRequest {
+            slots: self.future_slots.clone(),
+            slot: req_resp,
+            collected: false,
+            blk: self as *mut Self as *mut (),
+            wait: Self::wait_future,
+        }
+    }
+
+    /// Pop used entries until the request of the future in `slot` has
+    /// completed, for a future dropped in flight.
+    ///
+    /// # Safety
+    ///
+    /// `blk` must point to the driver which submitted the request, and
+    /// nothing else may use the driver meanwhile.
+    unsafe fn wait_future(blk: *mut (), slot: usize) {
+        let blk = &mut *(blk as *mut Self);
+        let mut spins = 0usize;
+        while blk.future_slots[slot].status().is_none() {
+            match blk.queue.pop_used() {
+                Ok((token, _)) => {
+                    blk.complete_background(token);
+                    if let Some(waker) = blk.take_waker(token) {
+                        waker.wake();
+                    }
+                }
+                Err(Error::NotReady) => {
+                    spins += 1;
+                    if spins == STALL_SPINS {
+                        warn!("device seems stalled: {:?}", blk.debug_queue_state());
+                    }
+                    spin_loop();
+                }
+                // the device may still access the buffer
+                Err(err) => panic!("failed to wait for a dropped future: {:?}", err),
+            }
+        }
+    }
+
+    /// Return the pairs of the futures which have collected their completion
+    /// to the pool.
+    fn reap_futures(&mut self) {
+        let slots = &self.future_slots;
+        let req_resp = &mut self.req_resp;
+        self.future_pairs.retain(|&pair| {
+            if slots[pair].state.load(Ordering::Acquire) == SLOT_FREE {
+                req_resp.release(pair);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    /// Notify the device of the request `token` and wrap it in a guard.
+    fn inflight(&mut self, token: u16, req_resp: usize) -> InflightRequest<'_, 'a> {
+        self.notify();
+        InflightRequest {
+            blk: self,
+            token,
+            req_resp,
+            done: false,
+            released: false,
+            _buf: PhantomData,
+        }
+    }
+
+    /// Notify the device unless it has asked not to be notified.
+    fn notify(&mut self) {
+        if self.queue.should_notify() {
+            self.header.notify(0, self.queue.avail_idx());
+        }
+    }
+
+    /// Capture the state of the queue, to diagnose a device which does not
+    /// complete the requests.
+    pub fn debug_queue_state(&mut self) -> QueueDebugInfo {
+        let mut info = self.queue.debug_info();
+        info.queue_enabled = self.header.queue_enabled(0);
+        info.notify_addr = self.header.notify_address(0);
+        info
+    }
+
+    /// Decode the descriptor chain of the request `token`, to check the
+    /// chain of a request which never completes.
+    pub fn dump_descriptor(&self, token: u16) -> DescChainDump {
+        self.queue.dump_descriptor(token)
+    }
+
+    /// Return the counters of the queue.
+    #[cfg(feature = "stats")]
+    pub fn queue_stats(&self) -> VirtQueueStats {
+        self.queue.stats()
+    }
+
+    /// Wait until the device has completed all the outstanding requests, so
+    /// that it no longer accesses their buffers, e.g. before a reset.
+    ///
+    /// The completed prefetches can still be read afterwards.
+    /// Return [`Error::Timeout`] if some requests are still in flight after
+    /// `max_spins` polls.
+    pub fn quiesce(&mut self, max_spins: usize) -> Result {
+        let mut spins = 0;
+        while self.queue.used_count() < self.queue.outstanding() {
+            if spins == max_spins {
+                return Err(Error::Timeout);
+            }
+            spins += 1;
+            spin_loop();
+        }
+        while let Ok((token, _)) = self.queue.pop_used() {
+            self.complete_background(token);
+        }
+        Ok(())
+    }
+
+    /// Add a request to the queue, notify the device and wait for it to
+    /// complete, return the used length.
+    fn submit_and_wait(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result<u32> {
+        // keep a direct chain contiguous if the free descriptors allow it,
+        // an indirect one takes a single descriptor of the queue
+        let count = inputs.len() + outputs.len();
+        if count > 1 && !self.queue.supports_indirect() {
+            if let Err(err) = self.queue.reserve(count) {
+                debug!("cannot reserve {} contiguous descriptors: {:?}", count, err);
+            }
+        }
+        let token = self.queue.add(inputs, outputs)?;
+        self.notify();
+        self.wait_for(token)
+    }
+
+    /// Pop used entries until the one of `token`, recording the completed
+    /// prefetches on the way.
+    ///
+    /// Return [`Error::Timeout`] if the device has not used any buffer after
+    /// a while, see [`VirtIOBlkPCI::give_up`].
+    fn wait_for(&mut self, token: u16) -> Result<u32> {
+        loop {
+            let mut spins = 0usize;
+            while self.queue.used_count() == 0 {
+                spins += 1;
+                if spins == STALL_SPINS {
+                    return Err(self.give_up());
+                }
+                spin_loop();
+            }
+            let (used, len) = self.queue.pop_used()?;
+            if used == token {
+                return Ok(len);
+            }
+            self.complete_background(used);
+        }
+    }
+
+    /// Pop used entries until the read in `slot` has completed, recording the
+    /// other completed reads and prefetches on the way.
+    fn wait_stream_read(&mut self, reads: &mut [Option<StreamRead>], slot: usize) -> Result {
+        while reads[slot].as_ref().map_or(false, |read| !read.done) {
+            let mut spins = 0usize;
+            while self.queue.used_count() == 0 {
+                spins += 1;
+                if spins == STALL_SPINS {
+                    return Err(self.give_up());
+                }
+                spin_loop();
+            }
+            let (used, _) = self.queue.pop_used()?;
+            match reads.iter_mut().flatten().find(|read| read.token == used) {
+                Some(read) => read.done = true,
+                None => self.complete_background(used),
+            }
+        }
+        Ok(())
+    }
+
+    /// Give up on a device which has not used any buffer after
+    /// [`STALL_SPINS`] polls, logging the state of the queue to help diagnose
+    /// it, and return [`Error::Timeout`].
+    ///
+    /// The device is reset and marked failed, so that it no longer accesses
+    /// the buffers of the requests in flight, which the callers free.
+    fn give_up(&mut self) -> Error {
+        warn!("device seems stalled, resetting it: {:?}", self.debug_queue_state());
+        if let Err(err) = self.header.hard_reset_and_reprobe() {
+            // the device may still access the buffers
+            panic!("failed to reset a stalled device: {:?}", err);
+        }
+        self.header.set_failed();
+        Error::Timeout
+    }
+
+    /// Record the completion of a prefetch or of a future found while waiting
+    /// for another request.
+    fn complete_background(&mut self, token: u16) {
+        if let Some(idx) = self.future_requests.iter().position(|&(t, _)| t == token) {
+            let (_, req_resp) = self.future_requests.swap_remove(idx);
+            let status = self.req_resp.resp(req_resp).status();
+            self.future_slots[req_resp].complete(status);
+            return;
+        }
+        self.complete_prefetch(token);
+    }
+
+    /// Mark the prefetch of `token` as done.
+    ///
+    /// A completed prefetch keeps its token until it is consumed, while the
+    /// queue may reuse it for a later one, so only those still in flight
+    /// match.
+    fn complete_prefetch(&mut self, token: u16) {
+        if let Some(prefetch) = self.prefetches.iter_mut().find(|p| p.token == token && !p.done) {
+            prefetch.done = true;
+        }
+    }
+
+    /// Copy a completed prefetch of the block into `buf`, return its status,
+    /// or `None` if there is no such prefetch.
+    fn read_prefetched(&mut self, block_id: usize, buf: &mut [u8]) -> Option<Result> {
+        while let Ok((token, _)) = self.queue.pop_used() {
+            self.complete_background(token);
+        }
+        let idx = self
+            .prefetches
+            .iter()
+            .position(|p| p.block_id == block_id && p.done)?;
+        let prefetch = self.prefetches.swap_remove(idx);
+        buf.copy_from_slice(unsafe { slice::from_raw_parts(prefetch.buf, BLK_SIZE) });
+        let status = self.req_resp.resp(prefetch.req_resp).status();
+        self.req_resp.release(prefetch.req_resp);
+        Some(status_to_result(status))
+    }
+
+    /// Wait for the prefetch of the block if there is one, and discard it.
+    fn discard_prefetch(&mut self, block_id: usize) -> Result {
+        if let Some(idx) = self.prefetches.iter().position(|p| p.block_id == block_id) {
+            if !self.prefetches[idx].done {
+                self.wait_for(self.prefetches[idx].token)?;
+            }
+            let prefetch = self.prefetches.swap_remove(idx);
+            self.req_resp.release(prefetch.req_resp);
+        }
+        Ok(())
+    }
+
+    /// Discard the prefetches of the `count` blocks from `block_id`.
+    fn discard_prefetches(&mut self, block_id: usize, count: usize) -> Result {
+        while let Some(block) = self
+            .prefetches
+            .iter()
+            .map(|p| p.block_id)
+            .find(|&block| block.wrapping_sub(block_id) < count)
+        {
+            self.discard_prefetch(block)?;
+        }
+        Ok(())
+    }
+
+    /// Record the checksums of the blocks written from `data`.
+    #[cfg(feature = "integrity-check")]
+    fn record_checksums(&mut self, block_id: usize, data: &[u8]) {
+        if let Some(checksums) = &mut self.checksums {
+            for (i, block) in data.chunks(BLK_SIZE).enumerate() {
+                checksums.insert(block_id + i, checksum(block));
+            }
+        }
+    }
+
+    /// Forget the checksums of `count` blocks whose content is not known.
+    #[cfg(feature = "integrity-check")]
+    fn forget_checksums(&mut self, block_id: usize, count: usize) {
+        if let Some(checksums) = &mut self.checksums {
+            for block in block_id..block_id + count {
+                checksums.remove(&block);
+            }
+        }
+    }
+
+    /// Check the blocks read into `data` against their recorded checksums.
+    #[cfg(feature = "integrity-check")]
+    fn verify_checksums(&self, block_id: usize, data: &[u8]) -> Result {
+        if let Some(checksums) = &self.checksums {
+            for (i, block) in data.chunks(BLK_SIZE).enumerate() {
+                if let Some(&expected) = checksums.get(&(block_id + i)) {
+                    let actual = checksum(block);
+                    if actual != expected {
+                        warn!(
+                            "block {:#x} read back with checksum {:#x}, expected {:#x}",
+                            block_id + i,
+                            actual,
+                            expected
+                        );
+                        return Err(Error::IntegrityCheckFailed);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl InflightRequest<'_, '_> {
+    /// The token of the request in the queue.
+    pub fn token(&self) -> u16 {
+        self.token
+    }
+
+    /// Register a waker to wake when [`VirtIOBlkPCI::handle_interrupt`] finds
+    /// the request completed.
+    pub fn register_waker(&mut self, waker: &Waker) {
+        self.blk.register_waker(self.token, waker);
+    }
+
+    /// Check whether the device has completed the request, without blocking.
+    pub fn poll(&mut self) -> Result<bool> {
+        while !self.done && self.blk.queue.can_pop() {
+            let (used, _) = self.blk.queue.pop_used()?;
+            if used == self.token {
+                self.done = true;
+            } else {
+                self.blk.complete_background(used);
+            }
+        }
+        Ok(self.done)
+    }
+
+    /// Wait for the request to complete and return its status.
+    pub fn wait(mut self) -> Result {
+        self.complete()
+    }
+
+    fn complete(&mut self) -> Result {
+        if !self.done {
+            self.blk.wait_for(self.token)?;
+            self.done = true;
+        }
+        // the token may be reused by the next request
+        self.blk.take_waker(self.token);
+        let status = self.blk.req_resp.resp(self.req_resp).status();
+        self.blk.req_resp.release(self.req_resp);
+        self.released = true;
+        status_to_result(status)
+    }
+}
+
+impl Drop for InflightRequest<'_, '_> {
+    fn drop(&mut self) {
+        if self.released {
+            return;
+        }
+        if !self.done {
+            warn!("request {} dropped while in flight, waiting for it", self.token);
+        }
+        if let Err(err) = self.complete() {
+            // the device may still access the buffer if the wait failed
+            if !self.done {
+                panic!("failed to wait for request {}: {:?}", self.token, err);
+            }
+        }
+    }
+}
+
+impl FutureSlot {
+    fn new() -> Self {
+        FutureSlot {
+            state: AtomicU16::new(SLOT_FREE),
+            locked: AtomicBool::new(false),
+            waker: UnsafeCell::new(None),
+        }
+    }
+
+    /// The status of the request, if it has completed.
+    fn status(&self) -> Option<RespStatus> {
+        match self.state.load(Ordering::Acquire) {
+            SLOT_FREE | SLOT_IN_FLIGHT => None,
+            status => Some(RespStatus::from(status as u8)),
+        }
+    }
+
+    /// Record the completion of the request and wake its task, unless the
+    /// future is registering its waker, and will see the completion then.
+    fn complete(&self, status: RespStatus) {
+        self.state.store(status as u16, Ordering::Release);
+        if self.locked.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_ok() {
+            // Safety: `locked` is held.
+            let waker = unsafe { (*self.waker.get()).take() };
+            self.locked.store(false, Ordering::Release);
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Replace the waker with `waker`, or drop it if `None`.
+    fn set_waker(&self, waker: Option<&Waker>) {
+        while self.locked.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
             spin_loop();
         }
-        self.queue.pop_used()?;
-        match resp.status() {
-            RespStatus::Ok => Ok(()),
-            _ => Err(Error::IoError),
+        // Safety: `locked` is held.
+        let registered = unsafe { &mut *self.waker.get() };
+        match (registered.as_mut(), waker) {
+            (Some(registered), Some(waker)) => registered.clone_from(waker),
+            (_, waker) => *registered = waker.cloned(),
+        }
+        self.locked.store(false, Ordering::Release);
+    }
+}
+
+impl FutureRequest {
+    /// Collect the completion if it has been recorded, otherwise register
+    /// the waker of `cx` and check again.
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<Result> {
+        assert!(!self.collected, "future polled after its completion");
+        let slot = &self.slots[self.slot];
+        let status = match slot.status() {
+            Some(status) => status,
+            None => {
+                slot.set_waker(Some(cx.waker()));
+                match slot.status() {
+                    Some(status) => status,
+                    None => return Poll::Pending,
+                }
+            }
+        };
+        self.collect();
+        Poll::Ready(status_to_result(status))
+    }
+
+    /// Drop the waker and let the driver return the pair to the pool.
+    fn collect(&mut self) {
+        let slot = &self.slots[self.slot];
+        slot.set_waker(None);
+        slot.state.store(SLOT_FREE, Ordering::Release);
+        self.collected = true;
+    }
+}
+
+impl Drop for FutureRequest {
+    fn drop(&mut self) {
+        if self.collected {
+            return;
+        }
+        if self.slots[self.slot].status().is_none() {
+            warn!("future dropped while in flight, waiting for it");
+            // Safety: the driver has not moved and is not in use, as required
+            // by `read_future` and `write_future`.
+            unsafe { (self.wait)(self.blk, self.slot) };
+        }
+        self.collect();
+    }
+}
+
+impl Future for ReadFuture<'_> {
+    type Output = Result;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        self.get_mut().request.poll(cx)
+    }
+}
+
+impl Future for WriteFuture<'_> {
+    type Output = Result;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result> {
+        self.get_mut().request.poll(cx)
+    }
+}
+
+impl BlockDevice for VirtIOBlkPCI<'_> {
+    fn read_block(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        VirtIOBlkPCI::read_block(self, block_id, buf)
+    }
+
+    fn write_block(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        VirtIOBlkPCI::write_block(self, block_id, buf)
+    }
+
+    fn block_size(&self) -> usize {
+        BLK_SIZE
+    }
+
+    fn num_blocks(&self) -> usize {
+        self.capacity
+    }
+
+    fn flush(&mut self) -> Result {
+        // without VIRTIO_BLK_F_FLUSH the device writes through, but one which
+        // offers it has a write cache the driver cannot flush if it has not
+        // been negotiated
+        let offered = BlkFeature::from_bits_truncate(self.header.offered_device_features());
+        if !offered.contains(BlkFeature::FLUSH) {
+            return Ok(());
+        }
+        VirtIOBlkPCI::flush(self)
+    }
+}
+
+/// The number of spins waiting for the device before giving up on it, see
+/// [`VirtIOBlkPCI::give_up`].
+const STALL_SPINS: usize = 1 << 24;
+
+/// The largest size of the queue, set by [`VirtIOBlkPCIBuilder::queue_size`].
+const MAX_QUEUE_SIZE: usize = 32;
+
+/// The FNV-1a hash of a block, for the integrity check.
+#[cfg(feature = "integrity-check")]
+fn checksum(data: &[u8]) -> u32 {
+    data.iter()
+        .fold(0x811c_9dc5, |hash, &byte| (hash ^ byte as u32).wrapping_mul(0x0100_0193))
+}
+
+/// The response of a request waited for with the response on the stack,
+/// guarded with the `dma-guard` feature.
+#[cfg(feature = "dma-guard")]
+type StackResp = GuardedResp;
+#[cfg(not(feature = "dma-guard"))]
+type StackResp = BlkResp;
+
+/// Convert the response of a completed request into a result, checking its
+/// guard bytes with the `dma-guard` feature.
+fn resp_to_result(resp: &StackResp) -> Result {
+    #[cfg(feature = "dma-guard")]
+    resp.check()?;
+    status_to_result(resp.status())
+}
+
+/// Convert the status of a request into a result.
+fn status_to_result(status: RespStatus) -> Result {
+    match status {
+        RespStatus::Ok => Ok(()),
+        RespStatus::Unsupported => Err(Error::Unsupported),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// Convert the status byte of a zoned request into a result, which has more
+/// values than [`RespStatus`].
+fn zone_status_to_result(status: u8) -> Result {
+    match status {
+        RESP_ZONE_INVALID_CMD | RESP_ZONE_UNALIGNED_WP => Err(Error::InvalidParam),
+        RESP_ZONE_OPEN_RESOURCE | RESP_ZONE_ACTIVE_RESOURCE => Err(Error::ZoneLimitReached),
+        status => status_to_result(RespStatus::from(status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::phys_to_virt;
+    use crate::pci::{InterruptMode, VirtIOPCIStructs};
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use core::sync::atomic::{fence, AtomicUsize};
+    use core::task::{RawWaker, RawWakerVTable};
+    use std::thread::{self, JoinHandle};
+
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+
+    fn counting_waker() -> Waker {
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(core::ptr::null(), &VTABLE)
+        }
+        fn wake(_: *const ()) {
+            WAKES.fetch_add(1, Ordering::SeqCst);
+        }
+        fn drop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake, drop);
+        unsafe { Waker::from_raw(RawWaker::new(core::ptr::null(), &VTABLE)) }
+    }
+
+    fn request(slots: &Arc<[FutureSlot]>) -> FutureRequest {
+        unsafe fn no_driver(_: *mut (), _: usize) {
+            panic!("request dropped in flight");
+        }
+        slots[0].state.store(SLOT_IN_FLIGHT, Ordering::Release);
+        FutureRequest {
+            slots: slots.clone(),
+            slot: 0,
+            collected: false,
+            blk: core::ptr::null_mut(),
+            wait: no_driver,
         }
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn future_slot_wakes_and_collects() {
+        let slots: Arc<[FutureSlot]> = (0..1).map(|_| FutureSlot::new()).collect();
+        let waker = counting_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // completed after the waker is registered
+        let mut req = request(&slots);
+        assert!(req.poll(&mut cx).is_pending());
+        let wakes = WAKES.load(Ordering::SeqCst);
+        slots[0].complete(RespStatus::Ok);
+        assert_eq!(WAKES.load(Ordering::SeqCst), wakes + 1);
+        assert_eq!(req.poll(&mut cx), Poll::Ready(Ok(())));
+        assert_eq!(slots[0].state.load(Ordering::Acquire), SLOT_FREE);
+        drop(req);
+
+        // completed before the first poll
+        let mut req = request(&slots);
+        slots[0].complete(RespStatus::IoErr);
+        assert_eq!(req.poll(&mut cx), Poll::Ready(Err(Error::IoError)));
+    }
+
+    #[test]
+    fn future_slot_completion_while_registering_is_not_missed() {
+        let slots: Arc<[FutureSlot]> = (0..1).map(|_| FutureSlot::new()).collect();
+        let mut req = request(&slots);
+        // the driver cannot take the waker while the future holds the flag
+        slots[0].locked.store(true, Ordering::Release);
+        slots[0].complete(RespStatus::Ok);
+        slots[0].locked.store(false, Ordering::Release);
+        let waker = counting_waker();
+        assert_eq!(req.poll(&mut Context::from_waker(&waker)), Poll::Ready(Ok(())));
+    }
+
+    /// The number of blocks of the fake device.
+    const FAKE_BLOCKS: usize = 8;
+
+    // offsets in the common configuration
+    const DEVICE_FEATURES: usize = 4;
+    const DEVICE_STATUS: usize = 20;
+    const QUEUE_SIZE: usize = 24;
+    const QUEUE_ENABLE: usize = 28;
+    const QUEUE_DESC: usize = 32;
+    const QUEUE_DRIVER: usize = 40;
+    const QUEUE_DEVICE: usize = 48;
+
+    /// ACKNOWLEDGE, DRIVER and FAILED, as left by a reset.
+    const GIVEN_UP_STATUS: u8 = 1 | 2 | 128;
+
+    const DESC_F_NEXT: u16 = 1;
+    const DESC_F_WRITE: u16 = 2;
+
+    /// The structures of a fake PCI block device.
+    #[repr(C, align(8))]
+    struct FakeStructs {
+        common_cfg: [u8; 64],
+        notify: [u8; 8],
+        isr: [u8; 8],
+        config: [u8; 512],
+    }
+
+    /// A block device emulated in memory, whose queue 0 is served by a
+    /// thread.
+    struct FakeDevice {
+        structs: Box<FakeStructs>,
+        stop: Arc<AtomicBool>,
+        flushes: Arc<AtomicUsize>,
+        thread: Option<JoinHandle<()>>,
+    }
+
+    impl FakeDevice {
+        /// Create a device offering `features` with VIRTIO_F_VERSION_1, as the
+        /// feature select register is not emulated and the low half of the
+        /// features is read as the high half too, where bit 0 is VERSION_1.
+        fn new(features: BlkFeature) -> Self {
+            let features = (features | BlkFeature::BARRIER).bits() as u32;
+            let mut structs = Box::new(FakeStructs { common_cfg: [0; 64], notify: [0; 8], isr: [0; 8], config: [0; 512] });
+            structs.common_cfg[DEVICE_FEATURES..DEVICE_FEATURES + 4].copy_from_slice(&features.to_le_bytes());
+            structs.common_cfg[QUEUE_SIZE..QUEUE_SIZE + 2].copy_from_slice(&16u16.to_le_bytes());
+            structs.config[..8].copy_from_slice(&(FAKE_BLOCKS as u64).to_le_bytes());
+            let stop = Arc::new(AtomicBool::new(false));
+            let flushes = Arc::new(AtomicUsize::new(0));
+            let common_cfg = structs.common_cfg.as_ptr() as usize;
+            let thread = {
+                let (stop, flushes) = (stop.clone(), flushes.clone());
+                // Safety: the structures outlive the thread, joined by `stop`.
+                thread::spawn(move || unsafe { serve(common_cfg, &stop, &flushes) })
+            };
+            FakeDevice {
+                structs,
+                stop,
+                flushes,
+                thread: Some(thread),
+            }
+        }
+
+        /// The header of the device, to be dropped before it.
+        fn header(&mut self) -> VirtIOPCIHeader {
+            let s = &mut *self.structs;
+            let structs = VirtIOPCIStructs {
+                common_cfg: s.common_cfg.as_mut_ptr() as u64,
+                notify_cfg: s.notify.as_mut_ptr() as u64,
+                isr_cfg: s.isr.as_mut_ptr() as u64,
+                device_cfg: s.config.as_mut_ptr() as u64,
+                notify_off_multiplier: 0,
+            };
+            // Safety: the structures live as long as the device.
+            let mut header = unsafe { VirtIOPCIHeader::new(0x1042, None, 1, [None, None, None, None, None, None], structs) };
+            header.set_interrupt_mode(InterruptMode::Poll);
+            header
+        }
+
+        fn flushes(&self) -> usize {
+            self.flushes.load(Ordering::SeqCst)
+        }
+
+        /// Stop serving the queue, before the driver frees it.
+        fn stop(&mut self) {
+            self.stop.store(true, Ordering::Release);
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap();
+            }
+        }
+    }
+
+    impl Drop for FakeDevice {
+        fn drop(&mut self) {
+            self.stop();
+        }
+    }
+
+    unsafe fn read<T>(addr: usize) -> T {
+        (addr as *const T).read_volatile()
+    }
+
+    unsafe fn write<T>(addr: usize, value: T) {
+        (addr as *mut T).write_volatile(value)
+    }
+
+    /// Serve the reads, writes and flushes of the queue 0 once it is enabled,
+    /// until `stop` is set.
+    unsafe fn serve(common_cfg: usize, stop: &AtomicBool, flushes: &AtomicUsize) {
+        let mut disk = vec![0u8; FAKE_BLOCKS * BLK_SIZE];
+        let mut last_avail = 0u16;
+        while !stop.load(Ordering::Acquire) {
+            fence(Ordering::SeqCst);
+            if read::<u16>(common_cfg + QUEUE_ENABLE) != 1 {
+                spin_loop();
+                continue;
+            }
+            let size = read::<u16>(common_cfg + QUEUE_SIZE);
+            let desc = phys_to_virt(read::<u64>(common_cfg + QUEUE_DESC) as usize);
+            let avail = phys_to_virt(read::<u64>(common_cfg + QUEUE_DRIVER) as usize);
+            let used = phys_to_virt(read::<u64>(common_cfg + QUEUE_DEVICE) as usize);
+            if read::<u16>(avail + 2) == last_avail {
+                spin_loop();
+                continue;
+            }
+            fence(Ordering::SeqCst);
+            let head = read::<u16>(avail + 4 + 2 * (last_avail % size) as usize);
+            last_avail = last_avail.wrapping_add(1);
+
+            // the buffers of the chain, as their address, length and
+            // whether they are writable
+            let mut buffers = Vec::new();
+            let mut id = head;
+            loop {
+                let entry = desc + 16 * id as usize;
+                let flags = read::<u16>(entry + 12);
+                let addr = phys_to_virt(read::<u64>(entry) as usize);
+                buffers.push((addr, read::<u32>(entry + 8) as usize, flags & DESC_F_WRITE != 0));
+                if flags & DESC_F_NEXT == 0 {
+                    break;
+                }
+                id = read::<u16>(entry + 14);
+            }
+            let req = buffers[0].0;
+            let (type_, sector) = (read::<u32>(req), read::<u64>(req + 8) as usize);
+            let mut offset = sector * BLK_SIZE;
+            let mut written = 1;
+            let mut status = RespStatus::Ok as u8;
+            for &(addr, len, writable) in &buffers[1..buffers.len() - 1] {
+                let block = &mut disk[offset..offset + len];
+                match (type_, writable) {
+                    (t, true) if t == ReqType::In as u32 => {
+                        slice::from_raw_parts_mut(addr as *mut u8, len).copy_from_slice(block);
+                        written += len;
+                    }
+                    (t, false) if t == ReqType::Out as u32 => {
+                        block.copy_from_slice(slice::from_raw_parts(addr as *const u8, len));
+                    }
+                    _ => status = RespStatus::Unsupported as u8,
+                }
+                offset += len;
+            }
+            if type_ == ReqType::Flush as u32 {
+                flushes.fetch_add(1, Ordering::SeqCst);
+            }
+            write::<u8>(buffers[buffers.len() - 1].0, status);
+
+            let used_idx = read::<u16>(used + 2);
+            let elem = used + 4 + 8 * (used_idx % size) as usize;
+            write::<u32>(elem, head as u32);
+            write::<u32>(elem + 4, written as u32);
+            fence(Ordering::SeqCst);
+            write::<u16>(used + 2, used_idx.wrapping_add(1));
+        }
+    }
+
+    #[test]
+    fn read_write_and_flush() {
+        let mut device = FakeDevice::new(BlkFeature::FLUSH);
+        let mut blk = VirtIOBlkPCI::new(device.header()).unwrap();
+        assert_eq!(blk.capacity, FAKE_BLOCKS);
+
+        let data: Vec<u8> = (0..BLK_SIZE).map(|i| i as u8).collect();
+        blk.write_block(3, &data).unwrap();
+        let mut buf = vec![0; BLK_SIZE];
+        blk.read_block(3, &mut buf).unwrap();
+        assert_eq!(buf, data);
+        blk.read_block(2, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0));
+
+        blk.flush().unwrap();
+        BlockDevice::flush(&mut blk).unwrap();
+        assert_eq!(device.flushes(), 2);
+        device.stop();
+    }
+
+    #[test]
+    fn flush_without_the_feature() {
+        // a write-back cache the driver cannot flush
+        let mut device = FakeDevice::new(BlkFeature::FLUSH);
+        let mut blk = VirtIOBlkPCI::builder(device.header())
+            .request_features(BlkFeature::SEG_MAX)
+            .build()
+            .unwrap();
+        assert_eq!(blk.flush(), Err(Error::Unsupported));
+        assert_eq!(BlockDevice::flush(&mut blk), Err(Error::Unsupported));
+        device.stop();
+
+        // a device writing through
+        let mut device = FakeDevice::new(BlkFeature::empty());
+        let mut blk = VirtIOBlkPCI::new(device.header()).unwrap();
+        assert_eq!(blk.flush(), Err(Error::Unsupported));
+        assert_eq!(BlockDevice::flush(&mut blk), Ok(()));
+        assert_eq!(device.flushes(), 0);
+        device.stop();
+    }
+
+    #[test]
+    fn stalled_device_times_out() {
+        let mut device = FakeDevice::new(BlkFeature::FLUSH);
+        let mut blk = VirtIOBlkPCI::new(device.header()).unwrap();
+        device.stop();
+        let mut buf = vec![0; BLK_SIZE];
+        assert_eq!(blk.read_block(0, &mut buf), Err(Error::Timeout));
+        // reset and marked failed, so that the device no longer accesses
+        // the buffer
+        let status = unsafe { read::<u8>(device.structs.common_cfg.as_ptr() as usize + DEVICE_STATUS) };
+        assert_eq!(status, GIVEN_UP_STATUS);
+    }
+}
@@ -0,0 +1,34 @@
+use super::{VirtIOBlkPCI, VirtIOPCIHeader, VirtIORngPCI};
+use crate::header::DeviceType;
+use crate::{Error, Result};
+
+/// A driver of any of the device types supported on a PCI bus, e.g. to keep
+/// the drivers created by a probe loop together.
+#[non_exhaustive]
+pub enum VirtIODevice<'a> {
+    /// A block device.
+    Block(VirtIOBlkPCI<'a>),
+    /// An entropy source.
+    Rng(VirtIORngPCI<'a>),
+}
+
+impl VirtIODevice<'_> {
+    /// Create the driver matching the type of the device.
+    ///
+    /// Return [`Error::Unsupported`] if there is no PCI driver for the type.
+    pub fn from_header(header: VirtIOPCIHeader) -> Result<Self> {
+        match header.device_type() {
+            DeviceType::Block => Ok(VirtIODevice::Block(VirtIOBlkPCI::new(header)?)),
+            DeviceType::EntropySource => Ok(VirtIODevice::Rng(VirtIORngPCI::new(header)?)),
+            _ => Err(Error::Unsupported),
+        }
+    }
+
+    /// Get the device type.
+    pub fn device_type(&self) -> DeviceType {
+        match self {
+            VirtIODevice::Block(_) => DeviceType::Block,
+            VirtIODevice::Rng(_) => DeviceType::EntropySource,
+        }
+    }
+}
@@ -5,7 +5,13 @@
 
 use bitflags::*;
 use volatile::{ReadOnly, Volatile};
-use crate::header::DeviceType;
+use crate::hal::phys_to_virt;
+use crate::header::{ConfigStruct, DeviceType};
+use crate::queue::Transport;
+use crate::{Error, Result};
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use core::mem::size_of;
 use pci::BAR;
 use log::*;
 
@@ -19,6 +25,8 @@ pub struct VirtIOPCICommonCfgRaw {
     driver_features_sel: Volatile<u32>,
     driver_features: Volatile<u32>,
     msix_config: Volatile<u16>,
+    /// The number of all the virtqueues of the device, not to be confused
+    /// with the number of request queues of a block device.
     num_queues: ReadOnly<u16>,
     device_status: Volatile<DeviceStatusU8>,
     config_generation: ReadOnly<u8>,
@@ -32,6 +40,10 @@ pub struct VirtIOPCICommonCfgRaw {
     queue_desc: Volatile<u64>,
     queue_driver: Volatile<u64>,
     queue_device: Volatile<u64>,
+
+    /* Since VirtIO v1.2 */
+    queue_notify_data: ReadOnly<u16>,
+    queue_reset: Volatile<u16>,
 }
 
 /// See VirtIO spec 4.1.4.
@@ -54,40 +66,405 @@ pub struct VirtIOPCINotifyCapRaw {
     nofity_off_multiplier: Volatile<u32>,
 }
 
+/// The PCI power management capability.
+/// See PCI Bus Power Management Interface spec v1.2 section 3.2.
+#[repr(C)]
+struct PciPmCapRaw {
+    cap_id: Volatile<u8>,
+    cap_next: Volatile<u8>,
+    pmc: Volatile<u16>,
+    pmcsr: Volatile<u16>,
+}
+
+/// An entry of the MSI-X table.
+/// See PCI Local Bus spec v3.0 section 6.8.2.
+#[repr(C)]
+struct MsixTableEntryRaw {
+    message_addr: Volatile<u32>,
+    message_upper_addr: Volatile<u32>,
+    message_data: Volatile<u32>,
+    vector_control: Volatile<u32>,
+}
+
+const _: [(); 16] = [(); core::mem::size_of::<MsixTableEntryRaw>()];
+
+/// A virtio vendor-specific PCI capability, as read from the PCI
+/// configuration space.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct VirtIOPCICap {
+    /// The type of the structure, `VIRTIO_PCI_CAP_*_CFG`.
+    pub cfg_type: u8,
+    /// The BAR the structure is located in.
+    pub bar: u8,
+    /// The offset of the structure within the BAR.
+    pub offset: u32,
+    /// The length of the structure.
+    pub length: u32,
+    /// The multiplier of `queue_notify_off`, only for a notification
+    /// capability.
+    pub notify_off_multiplier: u32,
+}
+
+impl VirtIOPCICap {
+    /// Read a capability from its address in the memory-mapped PCI
+    /// configuration space.
+    ///
+    /// # Safety
+    ///
+    /// `cap_addr` must point to a virtio vendor-specific capability.
+    pub unsafe fn read(cap_addr: usize) -> Self {
+        let raw = &*(cap_addr as *const VirtIOPCICapRaw);
+        let cfg_type = raw.cfg_type.read();
+        let notify_off_multiplier = if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+            (*(cap_addr as *const VirtIOPCINotifyCapRaw)).nofity_off_multiplier.read()
+        } else {
+            0
+        };
+        VirtIOPCICap {
+            cfg_type,
+            bar: raw.bar.read(),
+            offset: raw.offset.read(),
+            length: raw.length.read(),
+            notify_off_multiplier,
+        }
+    }
+
+    /// The virtual address of the structure if its BAR is a mapped memory BAR
+    /// which contains the whole structure.
+    ///
+    /// The BARs hold physical addresses, which are translated with
+    /// `phys_to_virt`.
+    fn address(&self, bars: &[Option<BAR>; 6]) -> Option<u64> {
+        match bars.get(self.bar as usize)? {
+            Some(BAR::Memory(base, len, ..))
+                if self.offset as u64 + self.length as u64 <= *len as u64 =>
+            {
+                Some(phys_to_virt((*base + self.offset as u64) as usize) as u64)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The virtual addresses the virtio structures of a device are mapped at,
+/// as located by its capabilities, for [`VirtIOPCIHeader::new`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct VirtIOPCIStructs {
+    /// The common configuration.
+    pub common_cfg: u64,
+    /// The notification structure, where the notify registers of the queues
+    /// start.
+    pub notify_cfg: u64,
+    /// The ISR status.
+    pub isr_cfg: u64,
+    /// The device-specific configuration, or 0 if the device has none.
+    pub device_cfg: u64,
+    /// The multiplier of `queue_notify_off`, from the notification
+    /// capability.
+    pub notify_off_multiplier: u32,
+}
+
 /// All information required by a virtio pci device.
 pub struct VirtIOPCIHeader {
     device_id: u16,
+    subsystem_id: Option<u16>,
+    revision_id: u8,
     bars: [Option<BAR>; 6],
     common_cfg: &'static mut VirtIOPCICommonCfgRaw,
+    /// The length of the common configuration, or 0 if unknown.
+    common_cfg_len: usize,
     notify_cap_addr: usize,
+    isr_cfg_addr: usize,
     device_cfg_addr: usize,
+    /// The length of the device-specific configuration, or 0 if unknown.
+    device_cfg_len: usize,
     notify_off_multiplier: u32,
+    interrupt_mode: InterruptMode,
+    /// Events read from the ISR status but not consumed yet.
+    pending_isr: IsrStatus,
+    /// Features offered by the device, read when it is reset.
+    device_features: u64,
+    /// Features negotiated in `begin_init`.
+    driver_features: u64,
+    /// Notify addresses of the enabled queues, indexed by queue, or 0.
+    notify_addrs: Vec<usize>,
+    /// The value last written to `queue_sel`, or `None` if unknown.
+    queue_sel: Option<u16>,
+    /// The address and the number of entries of the MSI-X table, if known.
+    msix_table: Option<(usize, u16)>,
+    /// The MSI-X vectors of the queues, as set by `set_queue_msix_vector`.
+    queue_vectors: QueueInterruptMap,
+    /// The address of the power management capability, if known.
+    pm_cap_addr: Option<usize>,
+    /// All virtio capabilities of the device, in the order of the capability
+    /// list, if it has been created from them.
+    caps: Vec<VirtIOPCICap>,
+}
+
+/// How the device delivers interrupts to the driver.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InterruptMode {
+    /// Legacy INTx, which may be shared with other devices. The ISR status
+    /// must be read to find out whether the interrupt came from this device.
+    Intx,
+    /// MSI-X, with dedicated vectors for the queues and the configuration.
+    /// The ISR status is not used and must not be read.
+    MsiX,
+    /// No interrupt at all, e.g. before the interrupt controller is up. The
+    /// vectors are set to `VIRTIO_MSI_NO_VECTOR`, the ISR status is not read,
+    /// and the driver finds completions with `VirtQueue::used_count`.
+    Poll,
 }
 
 impl VirtIOPCIHeader {
     /// Create a VirtIOPCIHeader.
-    /// Safety: Caller must guarantee the correctness of `common_cfg_base_addr` and 
-    /// `notify_cap_base_addr`.
+    ///
+    /// `subsystem_id` is the PCI subsystem ID, if the caller has read it, and
+    /// `revision_id` is the PCI revision ID.
+    ///
+    /// The interrupt mode defaults to [`InterruptMode::Intx`], use
+    /// [`VirtIOPCIHeader::set_interrupt_mode`] after enabling MSI-X.
+    ///
+    /// `structs` holds the virtual addresses the structures are mapped at,
+    /// while `bars` hold physical addresses.
+    ///
+    /// # Safety
+    ///
+    /// The addresses of `structs` must be those of the structures of this
+    /// device, mapped for the life of the header.
     pub unsafe fn new(
         device_id: u16,
+        subsystem_id: Option<u16>,
+        revision_id: u8,
         bars: [Option<BAR>; 6],
-        common_cfg_base_addr: u64,
-        notify_cap_base_addr: u64,
-        device_cfg_base_addr: u64,
-        notify_off_multiplier: u32,
+        structs: VirtIOPCIStructs,
     ) -> Self {
         Self {
             device_id,
+            subsystem_id,
+            revision_id,
             bars,
-            common_cfg: &mut *(common_cfg_base_addr as *mut VirtIOPCICommonCfgRaw),
-            notify_cap_addr: notify_cap_base_addr as usize,
-            device_cfg_addr: device_cfg_base_addr as usize,
-            notify_off_multiplier,
+            common_cfg: &mut *(structs.common_cfg as *mut VirtIOPCICommonCfgRaw),
+            common_cfg_len: 0,
+            notify_cap_addr: structs.notify_cfg as usize,
+            isr_cfg_addr: structs.isr_cfg as usize,
+            device_cfg_addr: structs.device_cfg as usize,
+            device_cfg_len: 0,
+            notify_off_multiplier: structs.notify_off_multiplier,
+            interrupt_mode: InterruptMode::Intx,
+            pending_isr: IsrStatus::empty(),
+            device_features: 0,
+            driver_features: 0,
+            notify_addrs: Vec::new(),
+            queue_sel: None,
+            msix_table: None,
+            queue_vectors: QueueInterruptMap::default(),
+            pm_cap_addr: None,
+            caps: Vec::new(),
+        }
+    }
+
+    /// Create a VirtIOPCIHeader from all virtio capabilities of the device.
+    ///
+    /// A device may offer several capabilities of the same type: for each
+    /// type, the first one in `caps` whose BAR is mapped and large enough is
+    /// used, as the order of the capability list is the order of preference
+    /// of the device. Return [`Error::InvalidParam`] if no usable common,
+    /// notification or ISR capability is found.
+    /// Ref: VirtIO spec v1.1 section 4.1.4
+    ///
+    /// `bars` hold the physical addresses of the BARs, as read from the PCI
+    /// configuration space: the structures are accessed through
+    /// `phys_to_virt`, which must map the memory BARs.
+    ///
+    /// # Safety
+    ///
+    /// `bars` and `caps` must be read from the PCI configuration space of
+    /// this device, and its memory BARs must stay mapped for the life of the
+    /// header.
+    pub unsafe fn from_caps(
+        device_id: u16,
+        subsystem_id: Option<u16>,
+        revision_id: u8,
+        bars: [Option<BAR>; 6],
+        caps: &[VirtIOPCICap],
+    ) -> Result<Self> {
+        let find = |cfg_type| {
+            caps.iter()
+                .filter(|cap| cap.cfg_type == cfg_type)
+                .find_map(|cap| Some((cap, cap.address(&bars)?)))
+        };
+        let (common_cap, common_cfg) = find(VIRTIO_PCI_CAP_COMMON_CFG).ok_or(Error::InvalidParam)?;
+        let (notify_cap, notify_cfg) = find(VIRTIO_PCI_CAP_NOTIFY_CFG).ok_or(Error::InvalidParam)?;
+        let (_, isr_cfg) = find(VIRTIO_PCI_CAP_ISR_CFG).ok_or(Error::InvalidParam)?;
+        // not all devices have a device-specific configuration
+        let (device_cfg, device_cfg_len) = find(VIRTIO_PCI_CAP_DEVICE_CFG)
+            .map_or((0, 0), |(cap, addr)| (addr, cap.length as usize));
+        let structs = VirtIOPCIStructs {
+            common_cfg,
+            notify_cfg,
+            isr_cfg,
+            device_cfg,
+            notify_off_multiplier: notify_cap.notify_off_multiplier,
+        };
+        let mut header = Self::new(device_id, subsystem_id, revision_id, bars, structs);
+        header.common_cfg_len = common_cap.length as usize;
+        header.device_cfg_len = device_cfg_len;
+        header.caps.extend_from_slice(caps);
+        Ok(header)
+    }
+
+    /// All virtio capabilities of the device, empty if it has not been
+    /// created by [`VirtIOPCIHeader::from_caps`].
+    pub fn capabilities(&self) -> &[VirtIOPCICap] {
+        &self.caps
+    }
+
+    /// Set the interrupt mode, as found while parsing the PCI capabilities.
+    ///
+    /// It must be called before the queues are enabled.
+    pub fn set_interrupt_mode(&mut self, mode: InterruptMode) {
+        self.interrupt_mode = mode;
+        if mode == InterruptMode::Poll {
+            self.disable_config_interrupt();
+        }
+    }
+
+    /// Get the interrupt mode.
+    pub fn interrupt_mode(&self) -> InterruptMode {
+        self.interrupt_mode
+    }
+
+    /// Get the PCI revision ID.
+    pub fn revision_id(&self) -> u8 {
+        self.revision_id
+    }
+
+    /// Whether this is a transitional device, which also offers the legacy
+    /// interface, so that legacy quirks may apply.
+    ///
+    /// Transitional devices have a revision ID of 0, and one of the
+    /// transitional device IDs (0x1000 to 0x103f).
+    /// Ref: VirtIO spec v1.1 section 4.1.2.1
+    pub fn is_transitional(&self) -> bool {
+        self.revision_id == 0 && (0x1000..=0x103f).contains(&self.device_id)
+    }
+
+    /// Locate the MSI-X table, as found in the MSI-X capability: `bir` is the
+    /// index of the BAR it is located in, `offset` its offset within the BAR
+    /// and `table_size` its number of entries.
+    ///
+    /// Return [`Error::InvalidParam`] if the BAR is not a mapped memory BAR
+    /// which contains the whole table. The table is accessed through
+    /// `phys_to_virt` of the physical address of the BAR.
+    ///
+    /// # Safety
+    ///
+    /// `bir`, `offset` and `table_size` must be read from the MSI-X capability
+    /// of this device, and `phys_to_virt` must map its memory BARs, as the
+    /// table is then written by [`VirtIOPCIHeader::program_msix_entry`].
+    pub unsafe fn set_msix_table(&mut self, bir: u8, offset: u32, table_size: u16) -> Result {
+        let table_len = (table_size as usize * size_of::<MsixTableEntryRaw>()) as u64;
+        match self.bars.get(bir as usize) {
+            Some(Some(BAR::Memory(base, len, ..))) if offset as u64 + table_len <= *len as u64 => {
+                self.msix_table = Some((phys_to_virt((*base + offset as u64) as usize), table_size));
+                Ok(())
+            }
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Program an entry of the MSI-X table, located by
+    /// [`VirtIOPCIHeader::set_msix_table`], with the message address and data
+    /// of the interrupt, and mask or unmask it.
+    ///
+    /// Return [`Error::InvalidParam`] if the table is not located, or if
+    /// `entry` is beyond it.
+    pub fn program_msix_entry(&mut self, entry: u16, addr: u64, data: u32, masked: bool) -> Result {
+        let (table_addr, table_size) = self.msix_table.ok_or(Error::InvalidParam)?;
+        if entry >= table_size {
+            return Err(Error::InvalidParam);
+        }
+        // Safety: the table has been located by `set_msix_table`.
+        let raw = unsafe {
+            &mut *((table_addr + entry as usize * size_of::<MsixTableEntryRaw>()) as *mut MsixTableEntryRaw)
+        };
+        // mask the vector while its message is being changed
+        let control = raw.vector_control.read();
+        raw.vector_control.write(control | MSIX_VECTOR_MASKED);
+        raw.message_addr.write(addr as u32);
+        raw.message_upper_addr.write((addr >> 32) as u32);
+        raw.message_data.write(data);
+        if !masked {
+            raw.vector_control.write(control & !MSIX_VECTOR_MASKED);
+        }
+        Ok(())
+    }
+
+    /// Locate the PCI power management capability, at `cap_addr` in the
+    /// memory-mapped PCI configuration space.
+    ///
+    /// Return [`Error::InvalidParam`] if it is not a power management
+    /// capability.
+    ///
+    /// # Safety
+    ///
+    /// `cap_addr` must point to a capability of the device, mapped for the
+    /// life of the header.
+    pub unsafe fn set_pm_capability(&mut self, cap_addr: usize) -> Result {
+        let raw = &*(cap_addr as *const PciPmCapRaw);
+        if raw.cap_id.read() != PCI_CAP_ID_PM {
+            return Err(Error::InvalidParam);
         }
+        self.pm_cap_addr = Some(cap_addr);
+        Ok(())
+    }
+
+    /// Get the power state of the device, 0 for D0 to 3 for D3hot, or `None`
+    /// if the power management capability is not located.
+    pub fn power_state(&self) -> Option<u8> {
+        // Safety: the capability has been located by `set_pm_capability`.
+        let raw = unsafe { &*(self.pm_cap_addr? as *const PciPmCapRaw) };
+        Some((raw.pmcsr.read() & PCI_PMCSR_STATE_MASK) as u8)
+    }
+
+    /// Bring the device to the D0 power state, as devices left in D3 by the
+    /// firmware read all ones from their virtio registers.
+    ///
+    /// It must be called before [`VirtIOPCIHeader::begin_init`], and the
+    /// caller must wait 10ms after a transition from D3hot before accessing
+    /// the device.
+    /// Return [`Error::Unsupported`] if the power management capability is
+    /// not located.
+    /// Ref: PCI Bus Power Management Interface spec v1.2 section 5.4
+    pub fn set_power_state_d0(&mut self) -> Result {
+        let cap_addr = self.pm_cap_addr.ok_or(Error::Unsupported)?;
+        // Safety: the capability has been located by `set_pm_capability`.
+        let raw = unsafe { &mut *(cap_addr as *mut PciPmCapRaw) };
+        let pmcsr = raw.pmcsr.read();
+        if pmcsr & PCI_PMCSR_STATE_MASK != 0 {
+            debug!("waking the device from D{}", pmcsr & PCI_PMCSR_STATE_MASK);
+            // writing the PME status back would clear it
+            raw.pmcsr.write(pmcsr & !(PCI_PMCSR_STATE_MASK | PCI_PMCSR_PME_STATUS));
+        }
+        Ok(())
     }
 
     /// Device type of this virtio-pci device.
+    ///
+    /// A modern device ID (0x1040 to 0x107f) takes precedence, then the PCI
+    /// subsystem ID, which legacy devices use to encode their type, then the
+    /// transitional device ID. An unknown device is [`DeviceType::Invalid`].
     pub fn device_type(&self) -> DeviceType {
+        if let 0x1040..=0x107f = self.device_id {
+            return DeviceType::from((self.device_id - 0x1040) as u32);
+        }
+        if let Some(subsystem_id) = self.subsystem_id {
+            let device_type = DeviceType::from(subsystem_id as u32);
+            if device_type != DeviceType::Invalid {
+                return device_type;
+            }
+        }
         match self.device_id {
             0x1000 => DeviceType::Network,
             0x1001 => DeviceType::Block,
@@ -97,31 +474,111 @@ impl VirtIOPCIHeader {
             0x1005 => DeviceType::EntropySource,
             0x1009 => DeviceType::_9P,
             _ => {
-                panic!("Unknown virtio device type, pci device_id = {}", self.device_id);
+                warn!("Unknown virtio device type, pci device_id = {}", self.device_id);
+                DeviceType::Invalid
             }
         }
     }
 
     /// Begin initializing the device.
     ///
+    /// VIRTIO_F_VERSION_1 is always negotiated, since only the modern
+    /// interface is supported. Return [`Error::LegacyUnsupported`] if the
+    /// device does not offer it, [`Error::Unsupported`] if it rejects the
+    /// features, or [`Error::Timeout`] if the device does not complete its
+    /// reset.
+    ///
     /// Ref: virtio 3.1.1 Device Initialization
-    pub fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) {
-        let mut flag = DeviceStatusU8::empty();
-        // reset the device
-        self.common_cfg.device_status.write(flag);
-        flag |= DeviceStatusU8::ACKNOWLEDGE;
+    pub fn begin_init(&mut self, negotiate_features: impl FnOnce(u64) -> u64) -> Result {
+        self.hard_reset_and_reprobe()?;
+        self.negotiate_features(negotiate_features)
+    }
+
+    /// Reset the device and bring it back to the ACKNOWLEDGE and DRIVER
+    /// states, with its features read again, ready for
+    /// [`VirtIOPCIHeader::negotiate_features`].
+    ///
+    /// The state of the previous initialization is discarded: the negotiated
+    /// features, the enabled queues and the pending ISR events.
+    /// Return [`Error::Timeout`] if the device does not complete the reset,
+    /// or [`Error::LegacyUnsupported`] if it does not offer VIRTIO_F_VERSION_1.
+    /// Ref: virtio 4.1.4.3.2 Common configuration structure layout
+    pub fn hard_reset_and_reprobe(&mut self) -> Result {
+        self.common_cfg.device_status.write(DeviceStatusU8::empty());
+        // the device presents 0 once it has been reset
+        let mut spins = 0;
+        while !self.common_cfg.device_status.read().is_empty() {
+            if spins == RESET_SPINS {
+                return Err(Error::Timeout);
+            }
+            spins += 1;
+            spin_loop();
+        }
+        self.queue_sel = None;
+        self.driver_features = 0;
+        self.notify_addrs.clear();
+        self.queue_vectors.clear();
+        self.pending_isr = IsrStatus::empty();
+
+        let mut flag = DeviceStatusU8::ACKNOWLEDGE;
         self.common_cfg.device_status.write(flag);
         flag |= DeviceStatusU8::DRIVER;
         self.common_cfg.device_status.write(flag);
 
-        let features = self.read_device_features();
-        self.write_driver_features(negotiate_features(features));
-        flag |= DeviceStatusU8::FEATURES_OK;
+        self.device_features = self.read_device_features();
+        if self.device_features & VIRTIO_F_VERSION_1 == 0 {
+            self.set_failed();
+            return Err(Error::LegacyUnsupported);
+        }
+        Ok(())
+    }
+
+    /// Get the features offered by the device, as read by the last reset.
+    ///
+    /// They are cached by [`VirtIOPCIHeader::begin_init`], as reading them
+    /// again writes the feature select register, so that a shared borrow is
+    /// enough to inspect them once the device is set up.
+    pub fn offered_device_features(&self) -> u64 {
+        self.device_features
+    }
+
+    /// Get the features offered by the device, an alias of
+    /// [`VirtIOPCIHeader::offered_device_features`].
+    pub fn device_features(&self) -> u64 {
+        self.offered_device_features()
+    }
+
+    /// Get the features offered by the device but not negotiated, e.g. to
+    /// find out why a feature is not active with
+    /// [`describe_transport_features`](crate::describe_transport_features).
+    pub fn unnegotiated_features(&self) -> u64 {
+        self.device_features & !self.driver_features
+    }
+
+    /// Negotiate the features, among those offered by the device, after
+    /// [`VirtIOPCIHeader::hard_reset_and_reprobe`].
+    ///
+    /// VIRTIO_F_VERSION_1 is always negotiated. VIRTIO_F_NOTIF_CONFIG_DATA
+    /// and VIRTIO_F_RING_RESET are not if the common configuration is too
+    /// short for their fields, see
+    /// [`VirtIOPCIHeader::has_extended_common_cfg`].
+    /// Return [`Error::Unsupported`] if the device does not accept the
+    /// features, after marking it failed.
+    pub fn negotiate_features(&mut self, negotiate_features: impl FnOnce(u64) -> u64) -> Result {
+        self.driver_features = negotiate_features(self.device_features) | VIRTIO_F_VERSION_1;
+        if !self.has_extended_common_cfg() {
+            self.driver_features &= !(VIRTIO_F_NOTIF_CONFIG_DATA | VIRTIO_F_RING_RESET);
+        }
+        self.write_driver_features(self.driver_features);
+        let flag = self.common_cfg.device_status.read() | DeviceStatusU8::FEATURES_OK;
         self.common_cfg.device_status.write(flag);
         let status = self.common_cfg.device_status.read();
         if !status.contains(DeviceStatusU8::FEATURES_OK) {
-            panic!("virtio pci device initialization failed");
+            warn!("the device rejected the features {:#x}", self.driver_features);
+            self.set_failed();
+            return Err(Error::Unsupported);
         }
+        Ok(())
     }
 
     /// Finish initializing the device.
@@ -130,7 +587,23 @@ impl VirtIOPCIHeader {
         self.common_cfg.device_status.write(flag | DeviceStatusU8::DRIVER_OK);
     }
 
-    /// Read device features.
+    /// Whether the device has set DEVICE_NEEDS_RESET, after an error it
+    /// cannot recover from.
+    pub fn needs_reset(&self) -> bool {
+        self.common_cfg.device_status.read().contains(DeviceStatusU8::DEVICE_NEEDS_RESET)
+    }
+
+    /// Tell the device that the driver has given up on it.
+    ///
+    /// Unlike a reset, the device keeps its state, so that the hypervisor
+    /// can react.
+    pub fn set_failed(&mut self) {
+        let flag = self.common_cfg.device_status.read();
+        self.common_cfg.device_status.write(flag | DeviceStatusU8::FAILED);
+    }
+
+    /// Read device features, see
+    /// [`VirtIOPCIHeader::offered_device_features`] for the cached ones.
     fn read_device_features(&mut self) -> u64 {
         self.common_cfg.device_features_sel.write(0); // device features [0, 32)
         let mut device_features_bits = self.common_cfg.device_features.read().into();
@@ -147,9 +620,56 @@ impl VirtIOPCIHeader {
         self.common_cfg.driver_features.write((driver_features >> 32) as u32);
     }
 
-    /// Whether the queue is in used.
+    /// Whether VIRTIO_F_ACCESS_PLATFORM has been negotiated, so that the
+    /// device accesses memory through an IOMMU and I/O virtual addresses must
+    /// be used instead of physical addresses.
+    pub fn access_platform(&self) -> bool {
+        self.driver_features & VIRTIO_F_ACCESS_PLATFORM != 0
+    }
+
+    /// Whether VIRTIO_F_NOTIFICATION_DATA has been negotiated, so that the
+    /// notifications must carry the available index, see [`NotifyData`].
+    pub fn notification_data(&self) -> bool {
+        self.driver_features & VIRTIO_F_NOTIFICATION_DATA != 0
+    }
+
+    /// Whether VIRTIO_F_IN_ORDER has been negotiated, so that the device uses
+    /// the buffers in the order they are made available.
+    pub fn in_order(&self) -> bool {
+        self.driver_features & VIRTIO_F_IN_ORDER != 0
+    }
+
+    /// Whether VIRTIO_F_INDIRECT_DESC has been negotiated, so that the driver
+    /// can make a table of indirect descriptors available as a single one.
+    pub fn indirect_desc(&self) -> bool {
+        self.driver_features & VIRTIO_F_INDIRECT_DESC != 0
+    }
+
+    /// Select the queue the `queue_*` registers refer to, unless it is
+    /// already selected.
+    fn select_queue(&mut self, queue: u16) {
+        if self.queue_sel != Some(queue) {
+            self.common_cfg.queue_sel.write(queue);
+            self.queue_sel = Some(queue);
+        }
+    }
+
+    /// Get the selected queue.
+    fn selected_queue(&self) -> u16 {
+        match self.queue_sel {
+            Some(queue) => queue,
+            None => self.common_cfg.queue_sel.read(),
+        }
+    }
+
+    /// Whether the queue has been configured, e.g. before a warm reset of
+    /// the driver, so that it does not need to be set up again.
+    ///
+    /// The queue is left selected, so that the following calls of
+    /// [`VirtIOPCIHeader::max_queue_size`], [`VirtIOPCIHeader::queue_set`]
+    /// or [`VirtIOPCIHeader::queue_enable`] refer to it.
     pub fn queue_used(&mut self, queue: u32) -> bool {
-        self.common_cfg.queue_sel.write(queue as u16);
+        self.select_queue(queue as u16);
         self.common_cfg.queue_desc.read() != 0
             || self.common_cfg.queue_driver.read() != 0
             || self.common_cfg.queue_device.read() != 0
@@ -160,25 +680,185 @@ impl VirtIOPCIHeader {
         self.common_cfg.queue_size.read() as u32
     }
 
-    /// Set queue.
-    pub fn queue_set(&mut self, queue: u32, size: u32, desc_table_paddr: u64, avail_paddr: u64, used_paddr: u64) {
-        self.common_cfg.queue_sel.write(queue as u16);
+    /// Set queue, return the size of the queue read back from the device,
+    /// which is 0 if it rejected the queue.
+    pub fn queue_set(&mut self, queue: u32, size: u32, desc_table_paddr: u64, avail_paddr: u64, used_paddr: u64) -> u16 {
+        self.select_queue(queue as u16);
         // Do not use legacy interface, thus we can negotiate the queue_size(equal to or lower than)
         self.common_cfg.queue_size.write(size as u16);
+        let size = self.common_cfg.queue_size.read();
         self.common_cfg.queue_desc.write(desc_table_paddr as u64);
         self.common_cfg.queue_driver.write(avail_paddr as u64);
         self.common_cfg.queue_device.write(used_paddr as u64);
+        size
     }
 
     /// Enable the current VirtQueue.
     /// According the VirtIO spec 4.1.4.3.2, all other VirtQueue fields should be set up
     /// before enabling the VirtQueue.
+    ///
+    /// The notify address of the VirtQueue is cached at this point.
+    /// In [`InterruptMode::Poll`], the interrupts of the VirtQueue are disabled.
     pub fn queue_enable(&mut self) {
+        let queue = self.selected_queue() as usize;
+        if self.interrupt_mode == InterruptMode::Poll {
+            self.disable_queue_interrupt(queue as u32);
+        }
+        self.cache_notify_address(queue);
         //info!("queue_enable={}", self.common_cfg.queue_enable.read());
         self.common_cfg.queue_enable.write(0x1);
         //info!("queue_enable={}", self.common_cfg.queue_enable.read());
     }
 
+    /// Take over a queue a previous owner of the device has enabled, without
+    /// reprogramming it, return its size and the addresses of its descriptor
+    /// table, available ring and used ring, or `None` if it is not enabled.
+    ///
+    /// The notify address of the VirtQueue is cached as by
+    /// [`VirtIOPCIHeader::queue_enable`].
+    pub fn queue_adopt(&mut self, queue: u16) -> Option<(u16, u64, u64, u64)> {
+        self.select_queue(queue);
+        if self.common_cfg.queue_enable.read() == 0 {
+            return None;
+        }
+        self.cache_notify_address(queue as usize);
+        Some((
+            self.common_cfg.queue_size.read(),
+            self.common_cfg.queue_desc.read(),
+            self.common_cfg.queue_driver.read(),
+            self.common_cfg.queue_device.read(),
+        ))
+    }
+
+    /// Cache the notify address of the selected queue.
+    fn cache_notify_address(&mut self, queue: usize) {
+        if self.notify_addrs.len() <= queue {
+            self.notify_addrs.resize(queue + 1, 0);
+        }
+        self.notify_addrs[queue] = self.queue_notify_address();
+    }
+
+    /// Whether the queue is enabled, as reported by the device.
+    pub fn queue_enabled(&mut self, queue: u32) -> bool {
+        self.select_queue(queue as u16);
+        self.common_cfg.queue_enable.read() == 1
+    }
+
+    /// Disable the interrupts of a queue by setting its MSI-X vector to
+    /// `VIRTIO_MSI_NO_VECTOR`, for a driver which polls the queue.
+    ///
+    /// It must be called before [`VirtIOPCIHeader::queue_enable`].
+    /// Ref: VirtIO spec v1.1 section 4.1.5.1.2
+    pub fn disable_queue_interrupt(&mut self, queue: u32) {
+        self.select_queue(queue as u16);
+        self.common_cfg.queue_msix_vector.write(VIRTIO_MSI_NO_VECTOR);
+        self.queue_vectors.set(queue, VIRTIO_MSI_NO_VECTOR);
+    }
+
+    /// Route the interrupts of a queue to an MSI-X vector, e.g. one per queue
+    /// so that each queue completes on its own CPU, and record it in the
+    /// [`QueueInterruptMap`].
+    ///
+    /// It must be called before [`VirtIOPCIHeader::queue_enable`]. Return
+    /// [`Error::InvalidParam`] if `vector` is beyond the MSI-X table, when it
+    /// has been located, or [`Error::Unsupported`] if the device could not
+    /// allocate resources for the vector and reads back
+    /// `VIRTIO_MSI_NO_VECTOR`.
+    /// Ref: VirtIO spec v1.1 section 4.1.5.1.2
+    pub fn set_queue_msix_vector(&mut self, queue: u32, vector: u16) -> Result {
+        if let Some((_, table_size)) = self.msix_table {
+            if vector >= table_size {
+                return Err(Error::InvalidParam);
+            }
+        }
+        self.select_queue(queue as u16);
+        self.common_cfg.queue_msix_vector.write(vector);
+        if self.common_cfg.queue_msix_vector.read() != vector {
+            self.queue_vectors.set(queue, VIRTIO_MSI_NO_VECTOR);
+            return Err(Error::Unsupported);
+        }
+        self.queue_vectors.set(queue, vector);
+        Ok(())
+    }
+
+    /// The MSI-X vectors of the queues.
+    pub fn interrupt_map(&self) -> &QueueInterruptMap {
+        &self.queue_vectors
+    }
+
+    /// Find the queue to drain when `vector` fires, see
+    /// [`QueueInterruptMap::queue_for_vector`].
+    pub fn queue_for_vector(&self, vector: u16) -> Option<u32> {
+        self.queue_vectors.queue_for_vector(vector)
+    }
+
+    /// Disable the configuration change interrupt by setting its MSI-X vector
+    /// to `VIRTIO_MSI_NO_VECTOR`.
+    ///
+    /// Like [`VirtIOPCIHeader::disable_queue_interrupt`], it should be called
+    /// before the queues are enabled.
+    pub fn disable_config_interrupt(&mut self) {
+        self.common_cfg.msix_config.write(VIRTIO_MSI_NO_VECTOR);
+    }
+
+    /// Whether the common configuration has the fields added by VirtIO v1.2,
+    /// `queue_notify_data` and `queue_reset`.
+    ///
+    /// The length of the common configuration is only known if the header
+    /// has been created by [`VirtIOPCIHeader::from_caps`], a device of an
+    /// older revision exposes a shorter one. Otherwise the fields are assumed
+    /// to be there, and only the features tell whether they are meaningful.
+    pub fn has_extended_common_cfg(&self) -> bool {
+        self.common_cfg_len == 0 || self.common_cfg_len >= COMMON_CFG_V1_2_LEN
+    }
+
+    /// Get the notification data of a queue, to be sent instead of its index
+    /// once VIRTIO_F_NOTIF_CONFIG_DATA has been negotiated.
+    ///
+    /// Return `None` if the feature is not negotiated, which it is not if the
+    /// common configuration is too short for the field.
+    /// Ref: VirtIO spec v1.2 section 4.1.4.3
+    pub fn queue_notify_data(&mut self, queue: u32) -> Option<u16> {
+        if self.driver_features & VIRTIO_F_NOTIF_CONFIG_DATA == 0 {
+            return None;
+        }
+        self.select_queue(queue as u16);
+        Some(self.common_cfg.queue_notify_data.read())
+    }
+
+    /// Reset a single queue without resetting the whole device.
+    ///
+    /// The queue must be set up and enabled again before it is reused, so
+    /// the VirtQueue of the driver has to be recreated.
+    /// Return [`Error::Unsupported`] if VIRTIO_F_RING_RESET is not negotiated,
+    /// which it is not if the common configuration is too short for the
+    /// `queue_reset` field, or [`Error::Timeout`] if the device does not
+    /// complete the reset.
+    /// Ref: VirtIO spec v1.2 section 2.6.1
+    pub fn queue_reset(&mut self, queue: u32) -> Result {
+        if self.driver_features & VIRTIO_F_RING_RESET == 0 {
+            return Err(Error::Unsupported);
+        }
+        self.select_queue(queue as u16);
+        self.common_cfg.queue_reset.write(1);
+        // the device presents 1 once the queue has been reset
+        let mut spins = 0;
+        while self.common_cfg.queue_reset.read() != 1 {
+            if spins == RESET_SPINS {
+                return Err(Error::Timeout);
+            }
+            spins += 1;
+            spin_loop();
+        }
+        // the queue is disabled until it is enabled again, and its vector
+        // has to be set again with the other fields
+        if let Some(addr) = self.notify_addrs.get_mut(queue as usize) {
+            *addr = 0;
+        }
+        self.queue_vectors.set(queue, VIRTIO_MSI_NO_VECTOR);
+        Ok(())
+    }
+
     /// Return the notify address of the current VirtQueue.
     /// It can be used by the driver to notify the device.
     /// Ref: VirtIO spec v1.1 section 4.1.4.4
@@ -190,13 +870,64 @@ impl VirtIOPCIHeader {
         self.notify_cap_addr + queue_notify_off * self.notify_off_multiplier as usize
     }
 
+    /// Return the notify address cached when the VirtQueue was enabled, or
+    /// `None` if the queue is not enabled.
+    pub fn notify_address(&self, queue_idx: u16) -> Option<usize> {
+        match self.notify_addrs.get(queue_idx as usize) {
+            Some(&addr) if addr != 0 => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Return a pointer to the notify register of a queue, so that a driver
+    /// notifying it in a hot path can write the queue index to it directly,
+    /// without going through [`VirtIOPCIHeader::notify`].
+    ///
+    /// The address cached when the queue was enabled is returned if there is
+    /// one, otherwise it is computed, selecting the queue, but not cached, as
+    /// the cache tells which queues are enabled.
+    ///
+    /// The pointer stays valid as long as the notification BAR is mapped,
+    /// which the caller of [`VirtIOPCIHeader::new`] guarantees for the life
+    /// of the header, but must not be used afterwards. It must only be
+    /// written with a 16-bit volatile write of the queue index, or of
+    /// [`NotifyData`] as a 32-bit write once VIRTIO_F_NOTIFICATION_DATA has
+    /// been negotiated, and only while the queue is enabled: the device must
+    /// not be notified of a queue it has been reset out of.
+    pub fn notify_ptr(&mut self, queue: u32) -> *mut u16 {
+        if let Some(addr) = self.notify_address(queue as u16) {
+            return addr as *mut u16;
+        }
+        self.select_queue(queue as u16);
+        self.queue_notify_address() as *mut u16
+    }
+
     /// Notify the device that a new request has been submitted.
-    /// Assuming that VIRTIO_F_NOTIFICATION_DATA has not been negotiated.
+    ///
+    /// `avail_idx` is the index of the available ring of the queue, which is
+    /// sent as [`NotifyData`] once VIRTIO_F_NOTIFICATION_DATA has been
+    /// negotiated, otherwise only the index of the queue is written.
+    /// The notify address is the one cached by
+    /// [`VirtIOPCIHeader::queue_enable`], or read from `queue_notify_off` as
+    /// by [`VirtIOPCIHeader::notify_ptr`] if there is none.
     /// Ref: VirtIO spec v1.1 section 4.1.5.2
-    pub fn notify(&mut self, queue_idx: u16) {
+    pub fn notify(&mut self, queue_idx: u16, avail_idx: u16) {
+        if self.notification_data() {
+            return self.notify_with_data(NotifyData::split(queue_idx, avail_idx));
+        }
         // Safety: The implementation of `queue_notify_address` needs to be correct.
         unsafe {
-            (self.queue_notify_address() as *mut u16).write_volatile(queue_idx);
+            self.notify_ptr(queue_idx as u32).write_volatile(queue_idx);
+        }
+    }
+
+    /// Notify the device with the notification data, which is required once
+    /// VIRTIO_F_NOTIFICATION_DATA has been negotiated.
+    /// Ref: VirtIO spec v1.1 section 4.1.5.2
+    pub fn notify_with_data(&mut self, data: NotifyData) {
+        // Safety: The implementation of `queue_notify_address` needs to be correct.
+        unsafe {
+            (self.notify_ptr(data.queue_idx() as u32) as *mut u32).write_volatile(data.value());
         }
     }
 
@@ -205,6 +936,320 @@ impl VirtIOPCIHeader {
         self.device_cfg_addr
     }
 
+    /// Map the device-specific configuration as `T` for reading only.
+    pub fn config<T: ConfigStruct>(&self) -> &T {
+        // Safety: `device_cfg_addr` is guaranteed to be correct by the caller of
+        // `new`, and `T` matches the layout of the configuration space.
+        unsafe { &*(self.device_cfg_addr as *const T) }
+    }
+
+    /// Map the device-specific configuration as `T` for writing, e.g. the
+    /// `writeback` field of the block device.
+    ///
+    /// # Safety
+    ///
+    /// Only the fields the VirtIO spec marks as writable by the driver may be
+    /// written, the device behavior is undefined for the others.
+    pub unsafe fn config_mut<T: ConfigStruct>(&mut self) -> &mut T {
+        // `device_cfg_addr` is guaranteed to be correct by the caller of `new`,
+        // and `T` matches the layout of the configuration space.
+        &mut *(self.device_cfg_addr as *mut T)
+    }
+
+    /// Get the first `len` bytes of the device-specific configuration, e.g. to
+    /// dump it or to drive a device type not modeled by this crate.
+    ///
+    /// The length is bounded by the one of the DEVICE_CFG capability, which
+    /// is only known if the header has been created by
+    /// [`VirtIOPCIHeader::from_caps`], so the slice is empty otherwise.
+    pub fn config_bytes(&self, len: usize) -> &[u8] {
+        let len = len.min(self.device_cfg_len);
+        if len == 0 {
+            return &[];
+        }
+        // Safety: the capability guarantees that `device_cfg_len` bytes are
+        // mapped at `device_cfg_addr`.
+        unsafe { core::slice::from_raw_parts(self.device_cfg_addr as *const u8, len) }
+    }
+
+    /// Get the configuration generation, which the device changes every time
+    /// the device-specific configuration changes.
+    pub fn config_generation(&self) -> u8 {
+        self.common_cfg.config_generation.read()
+    }
+
+    /// Spin until the configuration generation stops changing, i.e. two
+    /// consecutive reads return the same value, and return that generation.
+    ///
+    /// Return [`Error::Timeout`] if it is still changing after `max_spins` reads.
+    pub fn wait_config_stable(&self, max_spins: usize) -> Result<u8> {
+        let mut generation = self.config_generation();
+        for _ in 0..max_spins {
+            spin_loop();
+            let new_generation = self.config_generation();
+            if new_generation == generation {
+                return Ok(generation);
+            }
+            generation = new_generation;
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Read the ISR status with a single read. The device clears it on read,
+    /// so both events must be taken from the returned value.
+    /// Ref: VirtIO spec v1.1 section 4.1.4.5
+    pub fn read_isr(&mut self) -> IsrStatus {
+        // Safety: `isr_cfg_addr` is guaranteed to be correct by the caller of `new`.
+        let isr = unsafe { (self.isr_cfg_addr as *const u8).read_volatile() };
+        IsrStatus::from_bits_truncate(isr)
+    }
+
+    /// Read the ISR status and keep the events not consumed yet.
+    fn poll_isr(&mut self) {
+        let isr = self.read_isr();
+        self.pending_isr |= isr;
+    }
+
+    /// Acknowledge interrupt and return which events it signals.
+    ///
+    /// Under INTx the ISR status is read once: the device clears it on read,
+    /// so both the queue and the configuration events of the interrupt are
+    /// returned, with those kept by an earlier
+    /// [`VirtIOPCIHeader::config_changed`].
+    /// Under MSI-X the queue vectors are not shared, so the ISR status is
+    /// not read and a queue is always reported as updated, configuration
+    /// changes having their own vector. In [`InterruptMode::Poll`] there is
+    /// no interrupt to acknowledge, so no event is reported.
+    /// Ref: VirtIO spec v1.1 section 4.1.4.5
+    pub fn ack_interrupt(&mut self) -> AckResult {
+        match self.interrupt_mode {
+            InterruptMode::Intx => {
+                self.poll_isr();
+                let isr = core::mem::replace(&mut self.pending_isr, IsrStatus::empty());
+                AckResult {
+                    queue: isr.contains(IsrStatus::QUEUE_INTERRUPT),
+                    config: isr.contains(IsrStatus::CONFIG_INTERRUPT),
+                }
+            }
+            InterruptMode::MsiX => AckResult {
+                queue: true,
+                config: false,
+            },
+            InterruptMode::Poll => AckResult::default(),
+        }
+    }
+
+    /// Whether the device configuration has changed.
+    ///
+    /// A queue interrupt read at the same time is kept for
+    /// [`VirtIOPCIHeader::ack_interrupt`].
+    /// Under MSI-X configuration changes are signaled through the
+    /// `msix_config` vector, so the ISR status is not read and this always
+    /// returns false, as in [`InterruptMode::Poll`].
+    pub fn config_changed(&mut self) -> bool {
+        match self.interrupt_mode {
+            InterruptMode::Intx => {
+                self.poll_isr();
+                let config = self.pending_isr.contains(IsrStatus::CONFIG_INTERRUPT);
+                self.pending_isr.remove(IsrStatus::CONFIG_INTERRUPT);
+                config
+            }
+            InterruptMode::MsiX | InterruptMode::Poll => false,
+        }
+    }
+}
+
+impl Transport for VirtIOPCIHeader {
+    fn notify(&mut self, queue: u16, avail_idx: u16) {
+        VirtIOPCIHeader::notify(self, queue, avail_idx);
+    }
+
+    fn needs_reset(&self) -> bool {
+        VirtIOPCIHeader::needs_reset(self)
+    }
+}
+
+/// The MSI-X vector of each queue, as set by
+/// [`VirtIOPCIHeader::set_queue_msix_vector`], so that an interrupt handler
+/// knows which queue to drain without scanning all of them.
+///
+/// It is cleared when the device is reset.
+#[derive(Debug, Clone, Default)]
+pub struct QueueInterruptMap {
+    /// The vectors indexed by queue, or `VIRTIO_MSI_NO_VECTOR`.
+    vectors: Vec<u16>,
+}
+
+impl QueueInterruptMap {
+    /// Get the MSI-X vector of a queue, or `None` if it has none.
+    pub fn vector(&self, queue: u32) -> Option<u16> {
+        match self.vectors.get(queue as usize) {
+            Some(&vector) if vector != VIRTIO_MSI_NO_VECTOR => Some(vector),
+            _ => None,
+        }
+    }
+
+    /// Find the queue whose interrupts are routed to `vector`.
+    ///
+    /// If several queues share the vector, the one with the lowest index is
+    /// returned, and the handler has to drain the others as well.
+    pub fn queue_for_vector(&self, vector: u16) -> Option<u32> {
+        if vector == VIRTIO_MSI_NO_VECTOR {
+            return None;
+        }
+        self.vectors.iter().position(|&v| v == vector).map(|queue| queue as u32)
+    }
+
+    fn set(&mut self, queue: u32, vector: u16) {
+        let queue = queue as usize;
+        if self.vectors.len() <= queue {
+            if vector == VIRTIO_MSI_NO_VECTOR {
+                return;
+            }
+            self.vectors.resize(queue + 1, VIRTIO_MSI_NO_VECTOR);
+        }
+        self.vectors[queue] = vector;
+    }
+
+    fn clear(&mut self) {
+        self.vectors.clear();
+    }
+}
+
+/// The events signaled by an interrupt, as returned by
+/// [`VirtIOPCIHeader::ack_interrupt`].
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct AckResult {
+    /// A queue has been updated.
+    pub queue: bool,
+    /// The device configuration has changed.
+    pub config: bool,
+}
+
+/// The value written to the notify register when VIRTIO_F_NOTIFICATION_DATA
+/// has been negotiated.
+///
+/// Its layout is:
+/// - bits 0..16: the index of the queue.
+/// - bits 16..31: the offset of the next available ring entry (split ring), or
+///   the offset of the next available descriptor (packed ring).
+/// - bit 31: the wrap counter of the next available descriptor (packed ring),
+///   or the bit 15 of the available index (split ring).
+///
+/// Ref: VirtIO spec v1.1 section 2.7.23
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct NotifyData {
+    value: u32,
+}
+
+impl NotifyData {
+    /// Create notification data for a queue.
+    pub fn new(queue_idx: u16) -> Self {
+        NotifyData {
+            value: queue_idx as u32,
+        }
+    }
+
+    /// Set the offset of the next available entry, only its 15 lower bits are used.
+    pub fn next_off(mut self, next_off: u16) -> Self {
+        self.value = (self.value & !NOTIFY_NEXT_OFF_MASK)
+            | (((next_off as u32) << 16) & NOTIFY_NEXT_OFF_MASK);
+        self
+    }
+
+    /// Set the wrap counter of the next available entry.
+    pub fn next_wrap(mut self, next_wrap: bool) -> Self {
+        if next_wrap {
+            self.value |= NOTIFY_NEXT_WRAP;
+        } else {
+            self.value &= !NOTIFY_NEXT_WRAP;
+        }
+        self
+    }
+
+    /// Create notification data for a split ring from its available index.
+    pub fn split(queue_idx: u16, avail_idx: u16) -> Self {
+        Self::new(queue_idx)
+            .next_off(avail_idx)
+            .next_wrap(avail_idx & (1 << 15) != 0)
+    }
+
+    /// The index of the queue.
+    pub fn queue_idx(&self) -> u16 {
+        self.value as u16
+    }
+
+    /// The value to write to the notify register.
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+}
+
+/// Common configuration.
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+/// Notifications.
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+/// ISR status.
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+/// Device-specific configuration.
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+const NOTIFY_NEXT_OFF_MASK: u32 = 0x7fff << 16;
+const NOTIFY_NEXT_WRAP: u32 = 1 << 31;
+
+/// Feature bit indicating that the driver can use indirect descriptors.
+const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+
+/// Feature bit indicating that the device complies with virtio 1.0 or later.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Feature bit indicating that the device is behind an IOMMU.
+const VIRTIO_F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+/// The number of polls of the device status to wait for a reset.
+const RESET_SPINS: usize = 1 << 20;
+
+/// Feature bit indicating that the buffers are used in order.
+const VIRTIO_F_IN_ORDER: u64 = 1 << 35;
+
+/// Feature bit indicating that the notifications carry the available index.
+const VIRTIO_F_NOTIFICATION_DATA: u64 = 1 << 38;
+
+/// Feature bit indicating that the notification data of a queue is read
+/// from `queue_notify_data`.
+const VIRTIO_F_NOTIF_CONFIG_DATA: u64 = 1 << 39;
+
+/// Feature bit indicating that a single queue can be reset.
+const VIRTIO_F_RING_RESET: u64 = 1 << 40;
+
+/// The length of the common configuration with the fields added by VirtIO
+/// v1.2, up to `queue_reset`.
+const COMMON_CFG_V1_2_LEN: usize = 60;
+
+/// The ID of the PCI power management capability.
+const PCI_CAP_ID_PM: u8 = 0x01;
+
+/// The power state field of the power management control/status register.
+const PCI_PMCSR_STATE_MASK: u16 = 0b11;
+
+/// The PME status bit of the power management control/status register,
+/// cleared by writing 1.
+const PCI_PMCSR_PME_STATUS: u16 = 1 << 15;
+
+/// The bit of the vector control of an MSI-X table entry which masks it.
+const MSIX_VECTOR_MASKED: u32 = 1 << 0;
+
+/// The MSI-X vector value meaning that no interrupt is delivered.
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+bitflags! {
+    /// The ISR status.
+    pub struct IsrStatus: u8 {
+        /// A queue has been updated.
+        const QUEUE_INTERRUPT = 1 << 0;
+        /// The device configuration has changed.
+        const CONFIG_INTERRUPT = 1 << 1;
+    }
 }
 
 bitflags! {
@@ -234,4 +1279,56 @@ bitflags! {
         /// can’t recover.
         const DEVICE_NEEDS_RESET = 64;
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notify_data_layout() {
+        let data = NotifyData::new(3).next_off(0x1234).next_wrap(true);
+        assert_eq!(data.queue_idx(), 3);
+        assert_eq!(data.value(), 3 | 0x1234 << 16 | 1 << 31);
+        // only the 15 lower bits of the offset fit before the wrap bit
+        assert_eq!(NotifyData::new(0).next_off(0xffff).value(), 0x7fff << 16);
+        assert_eq!(data.next_wrap(false).value(), 3 | 0x1234 << 16);
+    }
+
+    #[test]
+    fn notify_data_split_ring() {
+        assert_eq!(NotifyData::split(1, 5).value(), 1 | 5 << 16);
+        // the bit 15 of the available index goes to the wrap bit
+        assert_eq!(NotifyData::split(1, 0x8005).value(), 1 | 5 << 16 | 1 << 31);
+        assert_eq!(NotifyData::split(0xffff, 0xffff).value(), u32::MAX);
+    }
+
+    /// Check the layout of the common configuration against the spec.
+    #[test]
+    fn common_cfg_layout() {
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, device_features_sel), 0);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, device_features), 4);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, driver_features_sel), 8);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, driver_features), 12);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, msix_config), 16);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, num_queues), 18);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, device_status), 20);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, config_generation), 21);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_sel), 22);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_size), 24);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_msix_vector), 26);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_enable), 28);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_notify_off), 30);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_desc), 32);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_driver), 40);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_device), 48);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_notify_data), 56);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_reset), 58);
+        assert_eq!(offset_of!(VirtIOPCICommonCfgRaw, queue_reset) + 2, COMMON_CFG_V1_2_LEN);
+    }
+
+    #[test]
+    fn pm_cap_layout() {
+        assert_eq!(offset_of!(PciPmCapRaw, pmc), 2);
+        assert_eq!(offset_of!(PciPmCapRaw, pmcsr), 4);
+    }
+}
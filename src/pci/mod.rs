@@ -1,5 +1,11 @@
 mod header;
 mod blk;
+mod device;
+mod rng;
 
-pub use header::VirtIOPCIHeader;
-pub use blk::VirtIOBlkPCI;
\ No newline at end of file
+pub use header::{AckResult, InterruptMode, IsrStatus, NotifyData, QueueInterruptMap, VirtIOPCICap, VirtIOPCIHeader, VirtIOPCIStructs};
+pub use blk::{
+    BatchCompletion, InflightRequest, InterruptOutcome, ReadFuture, VirtIOBlkPCI, VirtIOBlkPCIBuilder, WriteFuture,
+};
+pub use device::VirtIODevice;
+pub use rng::VirtIORngPCI;
@@ -0,0 +1,94 @@
+use super::VirtIOPCIHeader;
+use crate::header::DeviceType;
+use crate::queue::VirtQueue;
+use crate::{Error, Result};
+use alloc::vec::Vec;
+use core::hint::spin_loop;
+use log::*;
+
+/// The size of the request queue.
+const QUEUE_SIZE: usize = 8;
+
+/// The virtio entropy device, which fills the buffers given by the driver
+/// with random bytes, connected to a PCI bus.
+pub struct VirtIORngPCI<'a> {
+    header: VirtIOPCIHeader,
+    queue: VirtQueue<'a, QUEUE_SIZE>,
+    /// Refills completed but not collected yet, with the number of bytes
+    /// filled.
+    completed: Vec<(u16, usize)>,
+}
+
+impl<'a> VirtIORngPCI<'a> {
+    /// Create a new VirtIO-Rng PCI driver.
+    ///
+    /// Return [`Error::WrongDeviceType`] if the device is not an entropy
+    /// source.
+    pub fn new(mut header: VirtIOPCIHeader) -> Result<Self> {
+        if header.device_type() != DeviceType::EntropySource {
+            return Err(Error::WrongDeviceType);
+        }
+        // the device has no feature bits
+        header.begin_init(|_| 0)?;
+        let queue = VirtQueue::new_pci(&mut header, 0, QUEUE_SIZE as u16, false).map_err(|err| {
+            header.set_failed();
+            err
+        })?;
+        header.finish_init();
+        Ok(VirtIORngPCI {
+            header,
+            queue,
+            completed: Vec::new(),
+        })
+    }
+
+    /// Fill `buf` with random bytes, return the number of bytes filled, which
+    /// may be less than the length of `buf`.
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let token = unsafe { self.refill_async(buf)? };
+        loop {
+            if let Some(len) = self.try_complete_refill(token) {
+                return Ok(len);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Submit a request to fill `buf` with random bytes without waiting for
+    /// it, return its token for [`VirtIORngPCI::try_complete_refill`].
+    ///
+    /// # Safety
+    ///
+    /// `buf` is still borrowed by the underlying virtio entropy device even if
+    /// this method returns. Thus, it is the caller's responsibility to
+    /// guarantee that `buf` is not accessed until the refill completes.
+    pub unsafe fn refill_async(&mut self, buf: &mut [u8]) -> Result<u16> {
+        let token = self.queue.add(&[], &[buf])?;
+        if self.queue.should_notify() {
+            self.header.notify(0, self.queue.avail_idx());
+        }
+        Ok(token)
+    }
+
+    /// Return the number of bytes filled by the refill `token` if it has
+    /// completed, without blocking.
+    pub fn try_complete_refill(&mut self, token: u16) -> Option<usize> {
+        loop {
+            match self.queue.pop_used() {
+                Ok((used, len)) => self.completed.push((used, len as usize)),
+                Err(Error::NotReady) => break,
+                Err(err) => {
+                    warn!("failed to pop a refill: {:?}", err);
+                    break;
+                }
+            }
+        }
+        let idx = self.completed.iter().position(|&(used, _)| used == token)?;
+        Some(self.completed.swap_remove(idx).1)
+    }
+
+    /// Acknowledge interrupt and return true if the queue has been updated.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.header.ack_interrupt().queue
+    }
+}
@@ -1,3 +1,6 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::hint::spin_loop;
 use core::mem::size_of;
 use core::slice;
 use core::sync::atomic::{fence, Ordering};
@@ -5,7 +8,7 @@ use core::sync::atomic::{fence, Ordering};
 use super::*;
 use crate::header::VirtIOHeader;
 use crate::pci::VirtIOPCIHeader;
-use crate::hal::virt_to_phys;
+use crate::hal::{phys_to_virt, virt_to_iova, virt_to_phys};
 use bitflags::*;
 use log::*;
 
@@ -13,17 +16,18 @@ use volatile::Volatile;
 
 /// The mechanism for bulk data transport on virtio devices.
 ///
-/// Each device can have zero or more virtqueues.
+/// Each device can have zero or more virtqueues. `SIZE` is the largest number
+/// of entries of the queue, which sizes its rings at compile time.
 #[repr(C)]
-pub struct VirtQueue<'a> {
+pub struct VirtQueue<'a, const SIZE: usize> {
     /// DMA guard
-    dma: DMA,
+    memory: QueueMemory,
     /// Descriptor table
     desc: &'a mut [Descriptor],
     /// Available ring
-    avail: &'a mut AvailRing,
+    avail: &'a mut AvailRing<SIZE>,
     /// Used ring
-    used: &'a mut UsedRing,
+    used: &'a mut UsedRing<SIZE>,
 
     /// The index of queue
     queue_idx: u32,
@@ -32,38 +36,225 @@ pub struct VirtQueue<'a> {
     /// The number of used queues.
     num_used: u16,
     /// The head desc index of the free list.
+    ///
+    /// The list starts in ascending order, descriptors are taken from its
+    /// head and the chains popped are pushed back to it, so the most recently
+    /// freed descriptors are reused first. With VIRTIO_F_IN_ORDER, it is the
+    /// ring of the descriptors after the last one taken instead, in the order
+    /// of the table.
     free_head: u16,
     avail_idx: u16,
     last_used_idx: u16,
+    /// Whether VIRTIO_F_IN_ORDER has been negotiated.
+    in_order: bool,
+    /// The available ring index of the next buffer to complete, with
+    /// VIRTIO_F_IN_ORDER.
+    next_complete_idx: u16,
+    /// The id and length of the used entry of the batch being popped, with
+    /// VIRTIO_F_IN_ORDER.
+    batch_end: Option<(u16, u32)>,
+    /// The heads of the descriptor chains made available and not used yet,
+    /// one flag per descriptor.
+    in_use_heads: [bool; SIZE],
+    /// Whether the device accesses memory through an IOMMU.
+    access_platform: bool,
+    /// Tables of indirect descriptors for `VirtQueue::add_indirect`.
+    indirect: Option<IndirectPool<'a>>,
+    /// Counters for observability.
+    #[cfg(feature = "stats")]
+    stats: VirtQueueStats,
+}
+
+/// The transport of a device, through which [`VirtQueue::submit_and_wait`]
+/// notifies it and finds out that it has failed.
+pub trait Transport {
+    /// Notify the device that buffers are available in `queue`, whose
+    /// available ring has the index `avail_idx`, as the notification carries
+    /// it once VIRTIO_F_NOTIFICATION_DATA has been negotiated.
+    fn notify(&mut self, queue: u16, avail_idx: u16);
+    /// Whether the device has set DEVICE_NEEDS_RESET.
+    fn needs_reset(&self) -> bool;
+}
+
+/// A descriptor of a chain, as read from the descriptor table.
+#[derive(Debug, Clone, Copy)]
+pub struct DescDump {
+    /// The index of the descriptor in the table.
+    pub index: u16,
+    /// The address of the buffer, as seen by the device.
+    pub addr: u64,
+    /// The length of the buffer.
+    pub len: u32,
+    /// The raw flags of the descriptor.
+    pub flags: u16,
+    /// The next descriptor, meaningful if [`DescDump::has_next`].
+    pub next: u16,
+}
+
+impl DescDump {
+    /// Whether the chain continues with the descriptor `next`.
+    pub fn has_next(&self) -> bool {
+        self.flags & DescFlags::NEXT.bits() != 0
+    }
+
+    /// Whether the buffer is written by the device.
+    pub fn is_write(&self) -> bool {
+        self.flags & DescFlags::WRITE.bits() != 0
+    }
+
+    /// Whether the buffer contains a table of indirect descriptors.
+    pub fn is_indirect(&self) -> bool {
+        self.flags & DescFlags::INDIRECT.bits() != 0
+    }
+}
+
+/// A decoded descriptor chain, to diagnose a request which never completes.
+#[derive(Debug, Clone)]
+pub struct DescChainDump {
+    /// The head of the chain.
+    pub head: u16,
+    /// The descriptors of the chain, in order.
+    pub descs: Vec<DescDump>,
+    /// Whether the chain ends with a descriptor without the NEXT flag, rather
+    /// than a link out of the table or back into the chain.
+    pub complete: bool,
 }
 
-impl VirtQueue<'_> {
-    /// Create a new VirtQueue. Based on MMIO.
+/// The state of a queue, to diagnose a device which does not use the buffers.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueDebugInfo {
+    /// The index of the queue.
+    pub queue_idx: u32,
+    /// The index of the available ring, written by the driver.
+    pub avail_idx: u16,
+    /// The index of the used ring, written by the device.
+    pub used_idx: u16,
+    /// The index of the next used entry to pop.
+    pub last_used_idx: u16,
+    /// The number of descriptors in use.
+    pub num_used: u16,
+    /// Whether the device reports the queue as enabled.
+    pub queue_enabled: bool,
+    /// The address the driver writes to notify the device, if known.
+    pub notify_addr: Option<usize>,
+}
+
+/// Counters of a [`VirtQueue`], tracked with the `stats` feature.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct VirtQueueStats {
+    /// The total number of descriptors used.
+    pub descriptors_used: u64,
+    /// The number of notifications sent to the device.
+    pub notifications_sent: u64,
+    /// The number of notifications suppressed because the device asked not to be notified.
+    pub notifications_suppressed: u64,
+    /// The number of completions reclaimed from the used ring.
+    pub completions_reclaimed: u64,
+}
+
+impl<'a, const SIZE: usize> VirtQueue<'a, SIZE> {
+    /// Create a new VirtQueue of `size` entries, at most `SIZE`, in memory
+    /// allocated by the HAL. Based on MMIO.
     pub fn new(header: &mut VirtIOHeader, idx: usize, size: u16) -> Result<Self> {
         if header.queue_used(idx as u32) {
             return Err(Error::AlreadyUsed);
         }
-        if !size.is_power_of_two() || header.max_queue_size() < size as u32 {
+        // the device does not implement a queue whose maximum size is 0
+        if header.max_queue_size() == 0 {
+            return Err(Error::QueueUnavailable);
+        }
+        if !size.is_power_of_two() || size as usize > SIZE || header.max_queue_size() < size as u32 {
             return Err(Error::InvalidParam);
         }
         let layout = VirtQueueLayout::new(size);
         // alloc continuous pages
         let dma = DMA::new(layout.size / PAGE_SIZE)?;
+        let vaddr = dma.vaddr();
+        // Safety: the DMA memory is large enough and lives as long as the queue.
+        Ok(unsafe { Self::new_at(header, idx, size, vaddr, QueueMemory::Pages(dma)) })
+    }
 
-        header.queue_set(idx as u32, size as u32, PAGE_SIZE as u32, dma.pfn());
+    /// The number of bytes of DMA memory needed by a queue of `queue_size`
+    /// entries in the legacy layout: the descriptor table, the available ring
+    /// and the used ring, each padded as the layout requires.
+    ///
+    /// It is the footprint of the pages [`VirtQueue::new`] allocates, and the
+    /// size of the [`QueueRings`] given to [`VirtQueue::new_in`] for `SIZE`
+    /// entries. [`VirtQueue::new_pci`] allocates each ring on its own, with
+    /// less padding. `queue_size` must be a power of 2, as the constructors
+    /// require.
+    pub const fn dma_size(queue_size: u16) -> usize {
+        VirtQueueLayout::new(queue_size).size
+    }
 
-        let desc =
-            unsafe { slice::from_raw_parts_mut(dma.vaddr() as *mut Descriptor, size as usize) };
-        let avail = unsafe { &mut *((dma.vaddr() + layout.avail_offset) as *mut AvailRing) };
-        let used = unsafe { &mut *((dma.vaddr() + layout.used_offset) as *mut UsedRing) };
+    /// Create a new VirtQueue of `SIZE` entries in the rings provided by the
+    /// caller, e.g. in a static, without any allocation. Based on MMIO.
+    ///
+    /// # Safety
+    ///
+    /// Caller must guarantee that `rings` is physically contiguous.
+    pub unsafe fn new_in(header: &mut VirtIOHeader, idx: usize, rings: &'a mut QueueRings<SIZE>) -> Result<Self> {
+        if header.queue_used(idx as u32) {
+            return Err(Error::AlreadyUsed);
+        }
+        // the device does not implement a queue whose maximum size is 0
+        if header.max_queue_size() == 0 {
+            return Err(Error::QueueUnavailable);
+        }
+        if !SIZE.is_power_of_two() || header.max_queue_size() < SIZE as u32 {
+            return Err(Error::InvalidParam);
+        }
+        // the rings are zeroed as when allocated by the HAL
+        *rings = QueueRings::new();
+        let vaddr = rings as *mut QueueRings<SIZE> as usize;
+        Ok(Self::new_at(header, idx, SIZE as u16, vaddr, QueueMemory::Borrowed))
+    }
+
+    /// Set up a VirtQueue based on MMIO at `vaddr`.
+    ///
+    /// Safety: Caller must guarantee that the memory at `vaddr` holds the
+    /// layout of the queue and lives as long as it.
+    unsafe fn new_at(
+        header: &mut VirtIOHeader,
+        idx: usize,
+        size: u16,
+        vaddr: usize,
+        memory: QueueMemory,
+    ) -> Self {
+        let layout = VirtQueueLayout::new(size);
+        // VIRTIO_F_ACCESS_PLATFORM is not negotiated by the MMIO drivers
+        let access_platform = false;
+
+        let paddr = virt_to_phys(vaddr);
+        debug_assert!(
+            regions_disjoint(&[
+                (paddr, layout.desc_size),
+                (paddr + layout.avail_offset, layout.avail_size),
+                (paddr + layout.used_offset, layout.used_size),
+            ]),
+            "rings of queue {} overlap",
+            idx
+        );
+        header.queue_set(
+            idx as u32,
+            size as u32,
+            PAGE_SIZE as u32,
+            paddr,
+            paddr + layout.avail_offset,
+            paddr + layout.used_offset,
+        );
+
+        let desc = slice::from_raw_parts_mut(vaddr as *mut Descriptor, size as usize);
+        let avail = &mut *((vaddr + layout.avail_offset) as *mut AvailRing<SIZE>);
+        let used = &mut *((vaddr + layout.used_offset) as *mut UsedRing<SIZE>);
 
         // link descriptors together
         for i in 0..(size - 1) {
             desc[i as usize].next.write(i + 1);
         }
 
-        Ok(VirtQueue {
-            dma,
+        VirtQueue {
+            memory,
             desc,
             avail,
             used,
@@ -73,52 +264,106 @@ impl VirtQueue<'_> {
             free_head: 0,
             avail_idx: 0,
             last_used_idx: 0,
-        })
+            in_order: false,
+            next_complete_idx: 0,
+            batch_end: None,
+            in_use_heads: [false; SIZE],
+            access_platform,
+            indirect: None,
+            #[cfg(feature = "stats")]
+            stats: VirtQueueStats::default(),
+        }
     }
 
-    /// Create a new VirtQueue. Based on PCI bus.
-    pub fn new_pci(header: &mut VirtIOPCIHeader, idx: usize, size: u16) -> Result<Self> {
+    /// Create a new VirtQueue of `size` entries, at most `SIZE`. Based on PCI
+    /// bus.
+    ///
+    /// Each ring is allocated on its own, with the alignment the spec requires.
+    /// If `dma32` is set, the rings are placed in contiguous pages below 4GB
+    /// instead, or [`Error::DmaError`] is returned.
+    /// The size of the queue is the one the device reads back, which may be
+    /// smaller than `size`, or [`Error::QueueUnavailable`] is returned if the
+    /// device rejects it or does not implement the queue `idx`.
+    pub fn new_pci(header: &mut VirtIOPCIHeader, idx: usize, size: u16, dma32: bool) -> Result<Self> {
         if header.queue_used(idx as u32) {
             return Err(Error::AlreadyUsed);
         }
-        if !size.is_power_of_two() || header.max_queue_size() < size as u32 {
+        // the device does not implement a queue whose maximum size is 0
+        if header.max_queue_size() == 0 {
+            return Err(Error::QueueUnavailable);
+        }
+        if !size.is_power_of_two() || size as usize > SIZE || header.max_queue_size() < size as u32 {
             return Err(Error::InvalidParam);
         }
 
         let layout = VirtQueueLayout::new(size);
-        // alloc continuous pages
-        let dma = DMA::new(layout.size / PAGE_SIZE)?;
+        let (memory, desc_vaddr, avail_vaddr, used_vaddr) = if dma32 {
+            // alloc continuous pages
+            let dma = DMA::new_dma32(layout.size / PAGE_SIZE)?;
+            let vaddr = dma.vaddr();
+            let memory = QueueMemory::Pages(dma);
+            (memory, vaddr, vaddr + layout.avail_offset, vaddr + layout.used_offset)
+        } else {
+            // the modern interface does not need the rings to be contiguous,
+            // each of them is allocated with its own alignment
+            let desc = DMARegion::new(layout.desc_size, DESC_ALIGN)?;
+            let avail = DMARegion::new(layout.avail_size.max(size_of::<AvailRing<SIZE>>()), AVAIL_ALIGN)?;
+            let used = DMARegion::new(layout.used_size.max(size_of::<UsedRing<SIZE>>()), USED_ALIGN)?;
+            let vaddrs = (desc.vaddr(), avail.vaddr(), used.vaddr());
+            let memory = QueueMemory::Rings { desc, avail, used };
+            (memory, vaddrs.0, vaddrs.1, vaddrs.2)
+        };
 
-        let desc =
-            unsafe { slice::from_raw_parts_mut(dma.vaddr() as *mut Descriptor, size as usize) };
-        let avail = unsafe { &mut *((dma.vaddr() + layout.avail_offset) as *mut AvailRing) };
-        let used = unsafe { &mut *((dma.vaddr() + layout.used_offset) as *mut UsedRing) };
-
-        // This will lead to some memory consumption. However, currently it is acceptable.
-        let desc_table_paddr = virt_to_phys(desc.as_ptr() as *const _ as usize);
-        let avail_paddr = virt_to_phys(avail as *const _ as usize);
-        let used_paddr = virt_to_phys(used as *const _ as usize);
+        let avail = unsafe { &mut *(avail_vaddr as *mut AvailRing<SIZE>) };
+        let used = unsafe { &mut *(used_vaddr as *mut UsedRing<SIZE>) };
+
+        let access_platform = header.access_platform();
+        let desc_table_paddr = dma_addr(desc_vaddr, access_platform);
+        let avail_paddr = dma_addr(avail_vaddr, access_platform);
+        let used_paddr = dma_addr(used_vaddr, access_platform);
+        debug_assert!(
+            regions_disjoint(&[
+                (desc_table_paddr, layout.desc_size),
+                (avail_paddr, layout.avail_size),
+                (used_paddr, layout.used_size),
+            ]),
+            "rings of queue {} overlap",
+            idx
+        );
+        // the I/O virtual addresses may be above 4GB even if the memory is not
+        if dma32 && (used_paddr + layout.used_size) as u64 > 1 << 32 {
+            return Err(Error::DmaError);
+        }
         //info!("max_queue_size={}", header.max_queue_size());
-        info!("desc_vaddr={:p},avail_vaddr={:p},used_vaddr={:p}", desc as *const _, avail as *const _, used as *const _);
-        info!("desc_paddr={:#x},avail_paddr={:#x},used_paddr={:#x}", desc_table_paddr, avail_paddr, used_paddr);
+        debug!("desc_vaddr={:#x},avail_vaddr={:#x},used_vaddr={:#x}", desc_vaddr, avail_vaddr, used_vaddr);
+        debug!("desc_paddr={:#x},avail_paddr={:#x},used_paddr={:#x}", desc_table_paddr, avail_paddr, used_paddr);
 
-        header.queue_set(
+        // the device may not accept the size, what it reads back is the size of the ring
+        let queue_size = header.queue_set(
             idx as u32,
             size as u32,
             desc_table_paddr as u64,
             avail_paddr as u64,
             used_paddr as u64,
         );
+        // the rings have been laid out for the requested size, which must not grow
+        if queue_size == 0 || !queue_size.is_power_of_two() || queue_size > size {
+            return Err(Error::QueueUnavailable);
+        }
+        let size = queue_size;
+        let desc =
+            unsafe { slice::from_raw_parts_mut(desc_vaddr as *mut Descriptor, size as usize) };
 
-        // link descriptors together
-        for i in 0..(size - 1) {
-            desc[i as usize].next.write(i + 1);
+        // link descriptors together, in a ring, which VIRTIO_F_IN_ORDER
+        // walks in the order of the table
+        for i in 0..size {
+            desc[i as usize].next.write((i + 1) % size);
         }
 
         header.queue_enable();
 
         Ok(VirtQueue {
-            dma,
+            memory,
             desc,
             avail,
             used,
@@ -128,33 +373,187 @@ impl VirtQueue<'_> {
             free_head: 0,
             avail_idx: 0,
             last_used_idx: 0,
+            in_order: header.in_order(),
+            next_complete_idx: 0,
+            batch_end: None,
+            in_use_heads: [false; SIZE],
+            access_platform,
+            indirect: None,
+            #[cfg(feature = "stats")]
+            stats: VirtQueueStats::default(),
+        })
+    }
+
+    /// Create a new VirtQueue based on PCI bus, with a pool of `tables`
+    /// tables of `table_len` indirect descriptors for
+    /// [`VirtQueue::add_indirect`].
+    ///
+    /// The buffers added indirectly take a single descriptor of the queue, so
+    /// the queue can be smaller than the number of descriptors in flight,
+    /// and the tables as many as the requests. More tables than the size of
+    /// the queue would never be used, and are not allocated. The tables are
+    /// shortened to the size of the queue the device grants, which may be
+    /// smaller than `size`, as the spec forbids a longer chain.
+    /// Return [`Error::Unsupported`] if VIRTIO_F_INDIRECT_DESC has not been
+    /// negotiated, and [`Error::InvalidParam`] if a table is empty.
+    pub fn new_pci_indirect(
+        header: &mut VirtIOPCIHeader,
+        idx: usize,
+        size: u16,
+        dma32: bool,
+        tables: u16,
+        table_len: u16,
+    ) -> Result<Self> {
+        if !header.indirect_desc() {
+            return Err(Error::Unsupported);
+        }
+        if tables == 0 || table_len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let mut queue = Self::new_pci(header, idx, size, dma32)?;
+        let table_len = table_len.min(queue.queue_size);
+        let tables = tables.min(queue.queue_size);
+        let bytes = tables as usize * table_len as usize * size_of::<Descriptor>();
+        let dma = if dma32 {
+            DMA::new_dma32(pages(bytes))?
+        } else {
+            DMA::new(pages(bytes))?
+        };
+        let paddr = dma_addr(dma.vaddr(), queue.access_platform);
+        if dma32 && (paddr + bytes) as u64 > 1 << 32 {
+            return Err(Error::DmaError);
+        }
+        let descs = unsafe {
+            slice::from_raw_parts_mut(
+                dma.vaddr() as *mut Descriptor,
+                tables as usize * table_len as usize,
+            )
+        };
+        queue.indirect = Some(IndirectPool {
+            dma,
+            descs,
+            paddr,
+            table_len,
+            free: (0..tables).collect(),
+        });
+        Ok(queue)
+    }
+
+    /// Take over a VirtQueue a previous owner of the device, e.g. the kernel
+    /// before a kexec, has set up, without reprogramming the device or
+    /// zeroing the rings. Based on PCI bus.
+    ///
+    /// The indices are read from the live rings, and the descriptors are
+    /// free again. Return [`Error::NotReady`] if the queue is not enabled or
+    /// if the device has not used all the buffers made available, and
+    /// [`Error::InvalidParam`] if the queue is not the one the device knows.
+    ///
+    /// # Safety
+    ///
+    /// The rings must be at the given physical addresses, mapped by
+    /// `phys_to_virt`, and must not be freed nor used by anyone else while
+    /// the queue lives. The previous owner must be done with the queue: none
+    /// of its buffers may still be accessed by the device, and the device
+    /// must have been set up with the features `header` has negotiated.
+    pub unsafe fn adopt(
+        header: &mut VirtIOPCIHeader,
+        idx: usize,
+        desc_paddr: usize,
+        avail_paddr: usize,
+        used_paddr: usize,
+        size: u16,
+    ) -> Result<Self> {
+        if !size.is_power_of_two() || size as usize > SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let (queue_size, desc, avail, used) = header.queue_adopt(idx as u16).ok_or(Error::NotReady)?;
+        if (queue_size, desc, avail, used)
+            != (size, desc_paddr as u64, avail_paddr as u64, used_paddr as u64)
+        {
+            return Err(Error::InvalidParam);
+        }
+
+        let desc = slice::from_raw_parts_mut(phys_to_virt(desc_paddr) as *mut Descriptor, size as usize);
+        let avail = &mut *(phys_to_virt(avail_paddr) as *mut AvailRing<SIZE>);
+        let used = &mut *(phys_to_virt(used_paddr) as *mut UsedRing<SIZE>);
+        let avail_idx = avail.idx.read();
+        let used_idx = used.idx.read();
+        if avail_idx != used_idx {
+            return Err(Error::NotReady);
+        }
+
+        // no chain is outstanding, link all the descriptors together again,
+        // in a ring as by `new_pci`
+        for i in 0..size {
+            desc[i as usize].next.write((i + 1) % size);
+        }
+
+        Ok(VirtQueue {
+            memory: QueueMemory::Borrowed,
+            desc,
+            avail,
+            used,
+            queue_size: size,
+            queue_idx: idx as u32,
+            num_used: 0,
+            free_head: 0,
+            avail_idx,
+            last_used_idx: used_idx,
+            in_order: header.in_order(),
+            next_complete_idx: avail_idx,
+            batch_end: None,
+            in_use_heads: [false; SIZE],
+            access_platform: header.access_platform(),
+            indirect: None,
+            #[cfg(feature = "stats")]
+            stats: VirtQueueStats::default(),
         })
     }
 
     /// Add buffers to the virtqueue, return a token.
     ///
+    /// If the queue [supports indirect descriptors](VirtQueue::supports_indirect),
+    /// several buffers are added through a table as by
+    /// [`VirtQueue::add_indirect`], taking a single descriptor of the queue.
+    /// They are chained directly in the queue otherwise, or if they do not
+    /// fit in a table or no table is free.
+    ///
+    /// The descriptors are taken from the head of the free list, which starts
+    /// in ascending order, see [`VirtQueue::reserve`] to make the chain
+    /// contiguous once the queue has been in use.
+    /// If there are not enough free descriptors for the buffers, return
+    /// [Error::QueueFull], the caller can retry after some requests complete.
+    ///
     /// Ref: linux virtio_ring.c virtqueue_add
     pub fn add(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result<u16> {
         if inputs.is_empty() && outputs.is_empty() {
             return Err(Error::InvalidParam);
         }
-        if inputs.len() + outputs.len() + self.num_used as usize > self.queue_size as usize {
-            return Err(Error::BufferTooSmall);
+        let count = inputs.len() + outputs.len();
+        // a single buffer takes a single descriptor anyway
+        if let Some(pool) = &self.indirect {
+            if count > 1 && count <= pool.table_len as usize && !pool.free.is_empty() {
+                return self.add_indirect(inputs, outputs);
+            }
         }
+        if count + self.num_used as usize > self.queue_size as usize {
+            return Err(Error::QueueFull);
+        }
+        check_buffers(inputs, outputs)?;
 
         // allocate descriptors from free list
         let head = self.free_head;
         let mut last = self.free_head;
         for input in inputs.iter() {
             let desc = &mut self.desc[self.free_head as usize];
-            desc.set_buf(input);
+            desc.set_buf(input, self.access_platform);
             desc.flags.write(DescFlags::NEXT);
             last = self.free_head;
             self.free_head = desc.next.read();
         }
         for output in outputs.iter() {
             let desc = &mut self.desc[self.free_head as usize];
-            desc.set_buf(output);
+            desc.set_buf(output, self.access_platform);
             desc.flags.write(DescFlags::NEXT | DescFlags::WRITE);
             last = self.free_head;
             self.free_head = desc.next.read();
@@ -167,22 +566,199 @@ impl VirtQueue<'_> {
             desc.flags.write(flags);
         }
         self.num_used += (inputs.len() + outputs.len()) as u16;
+        #[cfg(feature = "stats")]
+        {
+            self.stats.descriptors_used += (inputs.len() + outputs.len()) as u64;
+        }
+
+        self.make_available(head);
+        Ok(head)
+    }
+
+    /// Add buffers to the virtqueue through a table of indirect descriptors
+    /// from the pool of [`VirtQueue::new_pci_indirect`], so that they take a
+    /// single descriptor of the queue, return a token.
+    ///
+    /// If no table or descriptor is free, return [Error::QueueFull], the
+    /// caller can retry after some requests complete. Return
+    /// [`Error::InvalidParam`] if the queue has no pool or if the buffers do
+    /// not fit in a table.
+    pub fn add_indirect(&mut self, inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result<u16> {
+        let count = inputs.len() + outputs.len();
+        let pool = self.indirect.as_mut().ok_or(Error::InvalidParam)?;
+        if count == 0 || count > pool.table_len as usize {
+            return Err(Error::InvalidParam);
+        }
+        if self.num_used == self.queue_size || pool.free.is_empty() {
+            return Err(Error::QueueFull);
+        }
+        check_buffers(inputs, outputs)?;
+
+        let table = pool.free.pop_front().unwrap();
+        let table_addr = pool.table_addr(table);
+        let descs = pool.table_mut(table);
+        let readable = inputs.len();
+        for (i, desc) in descs[..count].iter_mut().enumerate() {
+            if i < readable {
+                desc.set_buf(inputs[i], self.access_platform);
+                desc.flags.write(DescFlags::NEXT);
+            } else {
+                desc.set_buf(outputs[i - readable], self.access_platform);
+                desc.flags.write(DescFlags::NEXT | DescFlags::WRITE);
+            }
+            desc.next.write(i as u16 + 1);
+        }
+        // set last_elem.next = NULL
+        {
+            let desc = &mut descs[count - 1];
+            let mut flags = desc.flags.read();
+            flags.remove(DescFlags::NEXT);
+            desc.flags.write(flags);
+        }
+
+        // the descriptor of the table is alone in its chain, it must not
+        // have the NEXT flag
+        let head = self.free_head;
+        let desc = &mut self.desc[head as usize];
+        desc.addr.write(table_addr as u64);
+        desc.len.write((count * size_of::<Descriptor>()) as u32);
+        desc.flags.write(DescFlags::INDIRECT);
+        self.free_head = desc.next.read();
+        self.num_used += 1;
+        #[cfg(feature = "stats")]
+        {
+            self.stats.descriptors_used += 1;
+        }
+
+        self.make_available(head);
+        Ok(head)
+    }
+
+    /// Whether the queue has a pool of indirect descriptor tables, created by
+    /// [`VirtQueue::new_pci_indirect`] once VIRTIO_F_INDIRECT_DESC has been
+    /// negotiated, which [`VirtQueue::add`] uses.
+    pub fn supports_indirect(&self) -> bool {
+        self.indirect.is_some()
+    }
+
+    /// The number of chains of `count` buffers which can be in the queue at
+    /// the same time: one per table of the pool if [`VirtQueue::add`] puts
+    /// them in indirect tables, bounded by the size of the queue, otherwise
+    /// as many as the descriptors of the queue allow.
+    pub fn max_chains(&self, count: usize) -> usize {
+        match &self.indirect {
+            Some(pool) if count > 1 && count <= pool.table_len as usize => {
+                let tables = pool.descs.len() / pool.table_len as usize;
+                tables.min(self.queue_size as usize)
+            }
+            _ => self.queue_size as usize / count.max(1),
+        }
+    }
+
+    /// Put the chain of `head` in the available ring.
+    fn make_available(&mut self, head: u16) {
+        self.in_use_heads[head as usize] = true;
 
         let avail_slot = self.avail_idx & (self.queue_size - 1);
         self.avail.ring[avail_slot as usize].write(head);
 
-        // write barrier
+        // write barrier, so that the device sees the descriptors and the ring
+        // entry before the new index
         fence(Ordering::SeqCst);
 
         // increase head of avail ring
         self.avail_idx = self.avail_idx.wrapping_add(1);
         self.avail.idx.write(self.avail_idx);
-        Ok(head)
+    }
+
+    /// Add a command made of a header read by the device and a status written
+    /// by the device, without any data buffer, return a token.
+    pub fn add_command(&mut self, header: &[u8], status: &mut [u8]) -> Result<u16> {
+        self.add(&[header], &[status])
+    }
+
+    /// Add buffers to the virtqueue, notify the device unless it has asked
+    /// not to be, and wait for it to use them, return the used length.
+    ///
+    /// The device status is checked every so often while polling, so that
+    /// [`Error::NeedsReset`] is returned if the device has given up.
+    /// Return [`Error::Timeout`] if the buffers are not used after
+    /// `max_spins` polls. On either error the device may still access the
+    /// buffers, which must not be freed until it has been reset.
+    /// Other buffers used in the meantime are left for
+    /// [`VirtQueue::pop_used`].
+    pub fn submit_and_wait(
+        &mut self,
+        transport: &mut impl Transport,
+        inputs: &[&[u8]],
+        outputs: &[&mut [u8]],
+        max_spins: usize,
+    ) -> Result<u32> {
+        let token = self.add(inputs, outputs)?;
+        if self.should_notify() {
+            transport.notify(self.queue_idx as u16, self.avail_idx);
+        }
+        let mut spins = 0;
+        loop {
+            // the used ring is read with an acquire barrier by `reclaim`
+            match self.reclaim(token) {
+                Err(Error::NotReady) => {}
+                result => return result,
+            }
+            if spins % RESET_CHECK_SPINS == 0 && transport.needs_reset() {
+                return Err(Error::NeedsReset);
+            }
+            if spins == max_spins {
+                return Err(Error::Timeout);
+            }
+            spins += 1;
+            spin_loop();
+        }
     }
 
     /// Whether there is a used element that can pop.
+    ///
+    /// The used entries and the buffers the device has written are visible
+    /// once it returns true.
     pub fn can_pop(&self) -> bool {
-        self.last_used_idx != self.used.idx.read()
+        let used_idx = self.used.idx.read();
+        // read barrier, so that the used entries are not read before the index
+        fence(Ordering::Acquire);
+        self.last_used_idx != used_idx
+    }
+
+    /// The number of used elements that can pop.
+    ///
+    /// The used entries counted and their buffers are visible once it returns.
+    pub fn used_count(&self) -> u16 {
+        let used_idx = self.used.idx.read();
+        // read barrier, so that the used entries are not read before the index
+        fence(Ordering::Acquire);
+        used_idx.wrapping_sub(self.last_used_idx)
+    }
+
+    /// The index of the available ring, as last written by the driver.
+    pub fn avail_idx(&self) -> u16 {
+        self.avail_idx
+    }
+
+    /// The index of the used ring, as written by the device.
+    pub fn used_idx(&self) -> u16 {
+        let used_idx = self.used.idx.read();
+        // read barrier, so that the used entries are not read before the index
+        fence(Ordering::Acquire);
+        used_idx
+    }
+
+    /// The index of the next used entry to pop, equal to
+    /// [`VirtQueue::used_idx`] once all the used entries have been popped.
+    pub fn last_used_idx(&self) -> u16 {
+        self.last_used_idx
+    }
+
+    /// The number of buffers added and not popped yet, completed or not.
+    pub fn outstanding(&self) -> u16 {
+        self.in_use_heads.iter().filter(|&&in_use| in_use).count() as u16
     }
 
     /// The number of free descriptors.
@@ -190,10 +766,95 @@ impl VirtQueue<'_> {
         (self.queue_size - self.num_used) as usize
     }
 
+    /// Move the lowest run of `n` contiguous free descriptors to the head of
+    /// the free list, so that the chain of the next [`VirtQueue::add`] of `n`
+    /// buffers is contiguous, which the device walks faster.
+    ///
+    /// The other free descriptors keep their order. Return
+    /// [`Error::QueueFull`] if there is no such run, e.g. when the free
+    /// descriptors are fragmented, in which case the list is left untouched
+    /// and the chain can still be added.
+    ///
+    /// It does not allocate, so that it can be called for every request.
+    /// With VIRTIO_F_IN_ORDER, the descriptors must be taken in the order of
+    /// the table, so the list is left untouched.
+    pub fn reserve(&mut self, n: usize) -> Result {
+        if n > self.available_desc() {
+            return Err(Error::QueueFull);
+        }
+        if self.in_order {
+            return Ok(());
+        }
+        let (free, len) = self.free_list();
+        let free = &free[..len];
+        let mut is_free = [false; SIZE];
+        for &i in free {
+            is_free[i as usize] = true;
+        }
+        let start = (0..=self.queue_size as usize - n)
+            .find(|&start| is_free[start..start + n].iter().all(|&free| free))
+            .ok_or(Error::QueueFull)?;
+        let rest = free.iter().copied().filter(|&i| (i as usize) < start || i as usize >= start + n);
+        let mut order = [0; SIZE];
+        for (slot, i) in order.iter_mut().zip((start as u16..(start + n) as u16).chain(rest)) {
+            *slot = i;
+        }
+        self.relink_free_list(&order[..len]);
+        Ok(())
+    }
+
+    /// Sort the free list in ascending order again, as when the queue was
+    /// created, so that the descriptors are handed out deterministically.
+    ///
+    /// With VIRTIO_F_IN_ORDER, the list is already in the order of the table
+    /// and is left untouched.
+    pub fn sort_free_list(&mut self) {
+        if self.in_order {
+            return;
+        }
+        let (mut order, len) = self.free_list();
+        order[..len].sort_unstable();
+        self.relink_free_list(&order[..len]);
+    }
+
+    /// The free descriptors, in the order of the free list, and their number.
+    fn free_list(&self) -> ([u16; SIZE], usize) {
+        let mut free = [0; SIZE];
+        let len = self.available_desc();
+        let mut idx = self.free_head;
+        for slot in free[..len].iter_mut() {
+            *slot = idx;
+            idx = self.desc[idx as usize].next.read();
+        }
+        (free, len)
+    }
+
+    /// Link the free descriptors together in `order`.
+    fn relink_free_list(&mut self, order: &[u16]) {
+        if let Some(&head) = order.first() {
+            self.free_head = head;
+        }
+        for pair in order.windows(2) {
+            self.desc[pair[0] as usize].next.write(pair[1]);
+        }
+    }
+
     /// Recycle descriptors in the list specified by head.
     ///
     /// This will push all linked descriptors at the front of the free list.
     fn recycle_descriptors(&mut self, mut head: u16) {
+        if self.in_order {
+            // the chains complete in the order they were taken, so that the
+            // descriptors are back at the end of the ring of `free_head`
+            loop {
+                self.num_used -= 1;
+                let desc = &self.desc[head as usize];
+                if !desc.flags.read().contains(DescFlags::NEXT) {
+                    return;
+                }
+                head = desc.next.read();
+            }
+        }
         let origin_free_head = self.free_head;
         self.free_head = head;
         loop {
@@ -211,6 +872,9 @@ impl VirtQueue<'_> {
 
     /// Get a token from device used buffers, return (token, len).
     ///
+    /// Return [`Error::ProtocolError`] if the device has used a descriptor
+    /// which is not the head of an available chain.
+    ///
     /// Ref: linux virtio_ring.c virtqueue_get_buf_ctx
     pub fn pop_used(&mut self) -> Result<(u16, u32)> {
         if !self.can_pop() {
@@ -218,21 +882,371 @@ impl VirtQueue<'_> {
         }
         // read barrier
         fence(Ordering::SeqCst);
+        if self.in_order {
+            return self.pop_used_in_order();
+        }
 
         let last_used_slot = self.last_used_idx & (self.queue_size - 1);
-        let index = self.used.ring[last_used_slot as usize].id.read() as u16;
-        let len = self.used.ring[last_used_slot as usize].len.read();
+        let elem = &self.used.ring[last_used_slot as usize];
+        let (id, len) = (elem.id.read(), elem.len.read());
+        if !is_in_use_head(id, &self.in_use_heads[..self.queue_size as usize]) {
+            warn!("device used invalid descriptor {} of queue {}", id, self.queue_idx);
+            return Err(Error::ProtocolError);
+        }
+        self.retire(id as u16);
+        Ok((id as u16, len))
+    }
+
+    /// Get the next used buffer when VIRTIO_F_IN_ORDER has been negotiated.
+    ///
+    /// The device may write a single used entry for a batch of buffers, with
+    /// the id of the last one, and advance the used index by the number of
+    /// buffers: the buffers are popped one by one in the order they were made
+    /// available until that id, the length of the others being the total
+    /// length of their writable descriptors, as Linux does.
+    /// Ref: VirtIO spec v1.1 section 2.6.9
+    fn pop_used_in_order(&mut self) -> Result<(u16, u32)> {
+        let mask = self.queue_size - 1;
+        let (end_id, end_len) = match self.batch_end {
+            Some(end) => end,
+            None => {
+                // the entry of a batch is at the slot of its first buffer
+                let elem = &self.used.ring[(self.last_used_idx & mask) as usize];
+                let (id, len) = (elem.id.read(), elem.len.read());
+                if !is_in_use_head(id, &self.in_use_heads[..self.queue_size as usize]) {
+                    warn!("device used invalid descriptor {} of queue {}", id, self.queue_idx);
+                    return Err(Error::ProtocolError);
+                }
+                (id as u16, len)
+            }
+        };
+        let head = self.avail.ring[(self.next_complete_idx & mask) as usize].read();
+        let len = if head == end_id {
+            self.batch_end = None;
+            end_len
+        } else {
+            self.batch_end = Some((end_id, end_len));
+            self.writable_len(head)
+        };
+        self.next_complete_idx = self.next_complete_idx.wrapping_add(1);
+        self.retire(head);
+        Ok((head, len))
+    }
 
-        self.recycle_descriptors(index);
+    /// Return the chain of `head`, which the device has used, to the free
+    /// list, with its indirect table, and move past its used slot.
+    fn retire(&mut self, head: u16) {
+        self.in_use_heads[head as usize] = false;
+        let desc = &self.desc[head as usize];
+        if let Some(pool) = &mut self.indirect {
+            if desc.flags.read().contains(DescFlags::INDIRECT) {
+                pool.release(desc.addr.read() as usize);
+            }
+        }
+        self.recycle_descriptors(head);
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        #[cfg(feature = "stats")]
+        {
+            self.stats.completions_reclaimed += 1;
+        }
+    }
+
+    /// The total length of the writable descriptors of a chain, including
+    /// those of its indirect table.
+    fn writable_len(&self, head: u16) -> u32 {
+        let desc = &self.desc[head as usize];
+        if let Some(pool) = &self.indirect {
+            if desc.flags.read().contains(DescFlags::INDIRECT) {
+                let table = pool.table_of(desc.addr.read() as usize);
+                return chain_writable_len(pool.table(table), 0);
+            }
+        }
+        chain_writable_len(self.desc, head)
+    }
+
+    /// Reclaim the descriptors of a completed token which may not be the next
+    /// one to pop, return its used length.
+    ///
+    /// The used entries before it are kept in order for [`VirtQueue::pop_used`].
+    /// Return [`Error::NotReady`] if the token has not been used by the device.
+    /// If VIRTIO_F_IN_ORDER has been negotiated, the buffers complete in
+    /// order, so only the next one to pop can be reclaimed, the others are
+    /// not ready until those before have been popped.
+    pub fn reclaim(&mut self, token: u16) -> Result<u32> {
+        if self.in_order {
+            let front = self.avail.ring[(self.next_complete_idx & (self.queue_size - 1)) as usize].read();
+            if !self.can_pop() || front != token {
+                return Err(Error::NotReady);
+            }
+            let (_, len) = self.pop_used()?;
+            return Ok(len);
+        }
+        let used_idx = self.used.idx.read();
+        // read barrier
+        fence(Ordering::SeqCst);
+        let mask = self.queue_size - 1;
+        let mut idx = self.last_used_idx;
+        while idx != used_idx && self.used.ring[(idx & mask) as usize].id.read() != token as u32 {
+            idx = idx.wrapping_add(1);
+        }
+        if idx == used_idx {
+            return Err(Error::NotReady);
+        }
+        // move the entry to the front, the device does not write the entries
+        // it has already used
+        let len = self.used.ring[(idx & mask) as usize].len.read();
+        while idx != self.last_used_idx {
+            let prev = idx.wrapping_sub(1);
+            let (id, prev_len) = {
+                let elem = &self.used.ring[(prev & mask) as usize];
+                (elem.id.read(), elem.len.read())
+            };
+            let elem = &mut self.used.ring[(idx & mask) as usize];
+            elem.id.write(id);
+            elem.len.write(prev_len);
+            idx = prev;
+        }
+        let front = &mut self.used.ring[(idx & mask) as usize];
+        front.id.write(token as u32);
+        front.len.write(len);
+        let (_, len) = self.pop_used()?;
+        Ok(len)
+    }
+
+    /// Capture the state of the queue, the transport fields are left for the
+    /// driver to fill.
+    pub fn debug_info(&self) -> QueueDebugInfo {
+        QueueDebugInfo {
+            queue_idx: self.queue_idx,
+            avail_idx: self.avail.idx.read(),
+            used_idx: self.used.idx.read(),
+            last_used_idx: self.last_used_idx,
+            num_used: self.num_used,
+            queue_enabled: false,
+            notify_addr: None,
+        }
+    }
 
-        Ok((index, len))
+    /// Decode the descriptor chain starting at `head`, to check how the
+    /// driver built it.
+    ///
+    /// The walk stops at a descriptor out of the table or already visited,
+    /// in which case the dump is marked as incomplete.
+    pub fn dump_descriptor(&self, head: u16) -> DescChainDump {
+        let mut descs = Vec::new();
+        let mut visited = 0u64;
+        let mut idx = head;
+        loop {
+            if idx >= self.queue_size || visited & (1 << idx) != 0 {
+                return DescChainDump { head, descs, complete: false };
+            }
+            visited |= 1 << idx;
+            let desc = &self.desc[idx as usize];
+            let dump = DescDump {
+                index: idx,
+                addr: desc.addr.read(),
+                len: desc.len.read(),
+                flags: desc.flags.read().bits(),
+                next: desc.next.read(),
+            };
+            descs.push(dump);
+            if !dump.has_next() {
+                return DescChainDump { head, descs, complete: true };
+            }
+            idx = dump.next;
+        }
+    }
+
+    /// Discard all the entries the device has used, returning their
+    /// descriptors to the free list, and return the number of entries drained.
+    ///
+    /// The buffers of the discarded entries must not be expected to be processed.
+    pub fn drain(&mut self) -> usize {
+        let mut count = 0;
+        while self.pop_used().is_ok() {
+            count += 1;
+        }
+        count
     }
 
     /// Return size of the queue.
     pub fn size(&self) -> u16 {
         self.queue_size
     }
+
+    /// Whether the driver should notify the device after adding buffers.
+    ///
+    /// The device may set VRING_USED_F_NO_NOTIFY in the used ring to ask the
+    /// driver not to notify it, e.g. while it is busy processing the ring.
+    /// VIRTIO_F_EVENT_IDX is not negotiated by the drivers, so the flag is
+    /// the only means of suppression, and the other bits of the flags, which
+    /// the spec reserves, are ignored.
+    /// Ref: VirtIO spec v1.1 section 2.6.10.1
+    pub fn should_notify(&mut self) -> bool {
+        // read the flags after the avail idx has been written
+        fence(Ordering::SeqCst);
+        let notify = self.used.flags.read() & VRING_USED_F_NO_NOTIFY == 0;
+        #[cfg(feature = "stats")]
+        {
+            if notify {
+                self.stats.notifications_sent += 1;
+            } else {
+                self.stats.notifications_suppressed += 1;
+            }
+        }
+        notify
+    }
+
+    /// Ask the device not to interrupt the driver when it uses buffers, by
+    /// setting VRING_AVAIL_F_NO_INTERRUPT in the available ring, e.g. while
+    /// polling through a batch.
+    ///
+    /// It is only a hint, the device may still interrupt. After interrupts
+    /// are enabled again, the driver must check for used buffers once more,
+    /// as those used in the meantime do not raise an interrupt.
+    pub fn set_interrupt_suppression(&mut self, suppress: bool) {
+        let flags = self.avail.flags.read();
+        if suppress {
+            self.avail.flags.write(flags | VRING_AVAIL_F_NO_INTERRUPT);
+        } else {
+            self.avail.flags.write(flags & !VRING_AVAIL_F_NO_INTERRUPT);
+            // read the used idx after the flags have been written
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Return the counters of the queue.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> VirtQueueStats {
+        self.stats
+    }
+}
+
+/// The memory holding the rings of a VirtQueue, released with it.
+enum QueueMemory {
+    /// Contiguous pages, in the layout of the legacy interface.
+    Pages(DMA),
+    /// A region for each ring.
+    Rings {
+        desc: DMARegion,
+        avail: DMARegion,
+        used: DMARegion,
+    },
+    /// Provided by the caller of `VirtQueue::new_in`, or set up by a previous
+    /// owner of the device for `VirtQueue::adopt`.
+    Borrowed,
+}
+
+/// Tables of indirect descriptors, each taken by a chain of a single
+/// descriptor of the queue until the device has used it.
+///
+/// Ref: VirtIO spec v1.1 section 2.6.5.3
+struct IndirectPool<'a> {
+    /// DMA guard
+    dma: DMA,
+    /// The descriptors of all the tables, one after another.
+    descs: &'a mut [Descriptor],
+    /// The address of the first table, as seen by the device.
+    paddr: usize,
+    /// The number of descriptors of a table.
+    table_len: u16,
+    /// The tables not taken by a chain, taken from the front and returned to
+    /// the back, so that they are handed out in order.
+    free: VecDeque<u16>,
+}
+
+impl IndirectPool<'_> {
+    /// The address of `table`, as seen by the device.
+    fn table_addr(&self, table: u16) -> usize {
+        self.paddr + table as usize * self.table_len as usize * size_of::<Descriptor>()
+    }
+
+    /// The table at the address the device sees.
+    fn table_of(&self, addr: usize) -> u16 {
+        ((addr - self.paddr) / (self.table_len as usize * size_of::<Descriptor>())) as u16
+    }
+
+    fn table(&self, table: u16) -> &[Descriptor] {
+        let start = table as usize * self.table_len as usize;
+        &self.descs[start..start + self.table_len as usize]
+    }
+
+    fn table_mut(&mut self, table: u16) -> &mut [Descriptor] {
+        let start = table as usize * self.table_len as usize;
+        &mut self.descs[start..start + self.table_len as usize]
+    }
+
+    /// Return the table at the address the device sees to the pool.
+    fn release(&mut self, addr: usize) {
+        let table = self.table_of(addr);
+        self.free.push_back(table);
+    }
+}
+
+/// The number of polls of the used ring between two checks of the device
+/// status in [`VirtQueue::submit_and_wait`], as reading it may trap into the
+/// hypervisor.
+const RESET_CHECK_SPINS: usize = 1 << 10;
+
+/// The alignments of the rings, see VirtIO spec v1.1 section 2.6.
+const DESC_ALIGN: usize = 16;
+const AVAIL_ALIGN: usize = 2;
+const USED_ALIGN: usize = 4;
+
+/// The rings of a VirtQueue of `SIZE` entries, laid out at compile time as
+/// the legacy interface requires, see [`VirtQueue::new_in`].
+///
+/// It can be placed in a static on targets without an allocator.
+#[repr(C, align(4096))]
+pub struct QueueRings<const SIZE: usize> {
+    desc: [[u64; 2]; SIZE],
+    avail: AvailRingMem<SIZE>,
+    used: UsedRingMem<SIZE>,
+}
+
+/// The memory of an `AvailRing` in `QueueRings`, right after the descriptors.
+#[repr(C)]
+struct AvailRingMem<const SIZE: usize> {
+    flags: u16,
+    idx: u16,
+    ring: [u16; SIZE],
+    used_event: u16,
+}
+
+/// The memory of a `UsedRing` in `QueueRings`, on the next page.
+#[repr(C, align(4096))]
+struct UsedRingMem<const SIZE: usize> {
+    flags: u16,
+    idx: u16,
+    ring: [[u32; 2]; SIZE],
+    avail_event: u16,
+}
+
+impl<const SIZE: usize> QueueRings<SIZE> {
+    /// Zeroed rings.
+    pub const fn new() -> Self {
+        QueueRings {
+            desc: [[0; 2]; SIZE],
+            avail: AvailRingMem {
+                flags: 0,
+                idx: 0,
+                ring: [0; SIZE],
+                used_event: 0,
+            },
+            used: UsedRingMem {
+                flags: 0,
+                idx: 0,
+                ring: [[0; 2]; SIZE],
+                avail_event: 0,
+            },
+        }
+    }
+}
+
+impl<const SIZE: usize> Default for QueueRings<SIZE> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// The inner layout of a VirtQueue.
@@ -242,14 +1256,15 @@ struct VirtQueueLayout {
     avail_offset: usize,
     used_offset: usize,
     size: usize,
+    desc_size: usize,
+    avail_size: usize,
+    used_size: usize,
 }
 
 impl VirtQueueLayout {
-    fn new(queue_size: u16) -> Self {
-        assert!(
-            queue_size.is_power_of_two(),
-            "queue size should be a power of 2"
-        );
+    /// The layout of a queue of `queue_size` entries, which the callers check
+    /// to be a power of 2.
+    const fn new(queue_size: u16) -> Self {
         let queue_size = queue_size as usize;
         let desc = size_of::<Descriptor>() * queue_size;
         let avail = size_of::<u16>() * (3 + queue_size);
@@ -258,10 +1273,73 @@ impl VirtQueueLayout {
             avail_offset: desc,
             used_offset: align_up(desc + avail),
             size: align_up(desc + avail) + align_up(used),
+            desc_size: desc,
+            avail_size: avail,
+            used_size: used,
+        }
+    }
+}
+
+/// Whether `id`, as read from a used entry, is the head of a chain made
+/// available and not used yet, given the `in_use_heads` flags of the
+/// descriptors of a queue.
+fn is_in_use_head(id: u32, in_use_heads: &[bool]) -> bool {
+    in_use_heads.get(id as usize).copied().unwrap_or(false)
+}
+
+/// Check the buffers to add to a queue.
+///
+/// Return [`Error::InvalidParam`] if a buffer does not fit in a descriptor.
+fn check_buffers(inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result {
+    if !inputs.iter().all(|input| fits_in_descriptor(input.len()))
+        || !outputs.iter().all(|output| fits_in_descriptor(output.len()))
+    {
+        return Err(Error::InvalidParam);
+    }
+
+    // a buffer written by the device must not alias one it reads
+    debug_assert!(
+        inputs.iter().all(|input| outputs.iter().all(|output| regions_disjoint(&[
+            (input.as_ptr() as usize, input.len()),
+            (output.as_ptr() as usize, output.len()),
+        ]))),
+        "device-readable and device-writable buffers overlap"
+    );
+    Ok(())
+}
+
+/// The total length of the writable descriptors of the chain of `head` in
+/// the table `descs`.
+fn chain_writable_len(descs: &[Descriptor], mut head: u16) -> u32 {
+    let mut len = 0;
+    loop {
+        let desc = &descs[head as usize];
+        let flags = desc.flags.read();
+        if flags.contains(DescFlags::WRITE) {
+            len += desc.len.read();
         }
+        if !flags.contains(DescFlags::NEXT) {
+            return len;
+        }
+        head = desc.next.read();
     }
 }
 
+/// Whether a buffer of `len` bytes fits in a descriptor, whose `len` field
+/// is only 32 bits wide.
+fn fits_in_descriptor(len: usize) -> bool {
+    len <= u32::MAX as usize
+}
+
+/// Whether the regions, given as (address, size), do not overlap.
+fn regions_disjoint(regions: &[(usize, usize)]) -> bool {
+    regions.iter().enumerate().all(|(i, &(start, size))| {
+        regions[i + 1..]
+            .iter()
+            .all(|&(other, other_size)| start + size <= other || other + other_size <= start)
+    })
+}
+
 #[repr(C, align(16))]
 #[derive(Debug)]
 struct Descriptor {
@@ -272,13 +1350,23 @@ struct Descriptor {
 }
 
 impl Descriptor {
-    fn set_buf(&mut self, buf: &[u8]) {
-        self.addr.write(virt_to_phys(buf.as_ptr() as usize) as u64);
+    fn set_buf(&mut self, buf: &[u8], access_platform: bool) {
+        self.addr.write(dma_addr(buf.as_ptr() as usize, access_platform) as u64);
         //info!("set_buf va={:#x}, pa={:#x} len={:#x}", buf.as_ptr() as usize, self.addr.read(), buf.len());
         self.len.write(buf.len() as u32);
     }
 }
 
+/// Translate a virtual address to the address the device uses, which is an
+/// I/O virtual address if the device accesses memory through an IOMMU.
+fn dma_addr(vaddr: usize, access_platform: bool) -> usize {
+    if access_platform {
+        virt_to_iova(vaddr)
+    } else {
+        virt_to_phys(vaddr)
+    }
+}
+
 bitflags! {
     /// Descriptor flags
     struct DescFlags: u16 {
@@ -288,16 +1376,24 @@ bitflags! {
     }
 }
 
+/// The device sets it in the flags of the used ring when it does not need
+/// to be notified.
+const VRING_USED_F_NO_NOTIFY: u16 = 1;
+
+/// The driver sets it in the flags of the available ring when it does not
+/// need to be interrupted.
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
 /// The driver uses the available ring to offer buffers to the device:
 /// each ring entry refers to the head of a descriptor chain.
 /// It is only written by the driver and read by the device.
 #[repr(C)]
 #[derive(Debug)]
-struct AvailRing {
+struct AvailRing<const SIZE: usize> {
     flags: Volatile<u16>,
     /// A driver MUST NOT decrement the idx.
     idx: Volatile<u16>,
-    ring: [Volatile<u16>; 32], // actual size: queue_size
+    ring: [Volatile<u16>; SIZE], // actual size: queue_size
     used_event: Volatile<u16>, // unused
 }
 
@@ -305,10 +1401,10 @@ struct AvailRing {
 /// it is only written to by the device, and read by the driver.
 #[repr(C)]
 #[derive(Debug)]
-struct UsedRing {
+struct UsedRing<const SIZE: usize> {
     flags: Volatile<u16>,
     idx: Volatile<u16>,
-    ring: [UsedElem; 32],       // actual size: queue_size
+    ring: [UsedElem; SIZE],     // actual size: queue_size
     avail_event: Volatile<u16>, // unused
 }
 
@@ -318,3 +1414,146 @@ struct UsedElem {
     id: Volatile<u32>,
     len: Volatile<u32>,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+
+    #[repr(C)]
+    struct Rings {
+        desc: [Descriptor; 32],
+        avail: AvailRing<32>,
+        used: UsedRing<32>,
+    }
+
+    /// A queue in leaked memory, the test acting as the device.
+    fn queue(size: u16, in_order: bool) -> VirtQueue<'static, 32> {
+        // all the fields of the rings are integers, zero is valid for them
+        let rings: &'static mut Rings = Box::leak(Box::new(unsafe { core::mem::zeroed() }));
+        for i in 0..size {
+            rings.desc[i as usize].next.write((i + 1) % size);
+        }
+        VirtQueue {
+            memory: QueueMemory::Borrowed,
+            desc: &mut rings.desc[..size as usize],
+            avail: &mut rings.avail,
+            used: &mut rings.used,
+            queue_idx: 0,
+            queue_size: size,
+            num_used: 0,
+            free_head: 0,
+            avail_idx: 0,
+            last_used_idx: 0,
+            in_order,
+            next_complete_idx: 0,
+            batch_end: None,
+            in_use_heads: [false; 32],
+            access_platform: false,
+            indirect: None,
+            #[cfg(feature = "stats")]
+            stats: VirtQueueStats::default(),
+        }
+    }
+
+    /// Write a used entry and advance the used index by `buffers`.
+    fn device_use(queue: &mut VirtQueue<'_, 32>, id: u16, len: u32, buffers: u16) {
+        let idx = queue.used.idx.read();
+        let elem = &mut queue.used.ring[(idx & (queue.queue_size - 1)) as usize];
+        elem.id.write(id as u32);
+        elem.len.write(len);
+        queue.used.idx.write(idx.wrapping_add(buffers));
+    }
+
+    #[test]
+    fn queue_rings_have_the_legacy_layout() {
+        fn check<const SIZE: usize>() {
+            let layout = VirtQueueLayout::new(SIZE as u16);
+            assert_eq!(offset_of!(QueueRings<SIZE>, avail), layout.avail_offset);
+            assert_eq!(offset_of!(QueueRings<SIZE>, used), layout.used_offset);
+            assert_eq!(size_of::<QueueRings<SIZE>>(), layout.size);
+        }
+        check::<1>();
+        check::<16>();
+        check::<256>();
+        check::<1024>();
+    }
+
+    #[test]
+    fn in_order_batches_retire_every_buffer() {
+        let mut queue = queue(8, true);
+        let input = [0u8; 16];
+        let mut outputs = [[0u8; 8]; 6];
+        let (first, rest) = outputs.split_at_mut(3);
+        let tokens = [
+            queue.add(&[&input], &[&mut first[0]]).unwrap(),
+            queue.add(&[], &[&mut first[1]]).unwrap(),
+            queue.add(&[&input], &[&mut first[2]]).unwrap(),
+        ];
+        // descriptors are taken in the order of the table
+        assert_eq!(tokens, [0, 2, 3]);
+
+        // a single entry for the three buffers, with the id of the last one
+        device_use(&mut queue, tokens[2], 5, 3);
+        assert_eq!(queue.reclaim(tokens[1]), Err(Error::NotReady));
+        assert_eq!(queue.pop_used(), Ok((tokens[0], 8)));
+        assert_eq!(queue.pop_used(), Ok((tokens[1], 8)));
+        assert_eq!(queue.pop_used(), Ok((tokens[2], 5)));
+        assert_eq!(queue.pop_used(), Err(Error::NotReady));
+        assert_eq!(queue.num_used, 0);
+
+        // descriptors go on from where the last chain ended, around the ring
+        let (second, third) = rest.split_at_mut(2);
+        assert_eq!(queue.add(&[&input], &[&mut second[0]]), Ok(5));
+        assert_eq!(queue.add(&[], &[&mut second[1]]), Ok(7));
+        assert_eq!(queue.add(&[&input], &[&mut third[0]]), Ok(0));
+        device_use(&mut queue, 5, 1, 1);
+        assert_eq!(queue.reclaim(5), Ok(1));
+        device_use(&mut queue, 0, 2, 2);
+        assert_eq!(queue.pop_used(), Ok((7, 8)));
+        assert_eq!(queue.pop_used(), Ok((0, 2)));
+        assert_eq!(queue.num_used, 0);
+    }
+
+    #[test]
+    fn used_id_must_be_an_in_use_head() {
+        let mut in_use_heads = [false; 16];
+        for &head in &[0, 5, 7, 8] {
+            in_use_heads[head] = true;
+        }
+        let in_use_heads = &in_use_heads[..8];
+        assert!(is_in_use_head(0, in_use_heads));
+        assert!(is_in_use_head(7, in_use_heads));
+        // a descriptor which is free or in the middle of a chain
+        assert!(!is_in_use_head(1, in_use_heads));
+        // beyond the queue, even if the flag happens to be set
+        assert!(!is_in_use_head(8, in_use_heads));
+        assert!(!is_in_use_head(u32::MAX, in_use_heads));
+    }
+
+    #[test]
+    fn buffers_must_fit_in_a_descriptor() {
+        assert!(fits_in_descriptor(0));
+        assert!(fits_in_descriptor(u32::MAX as usize));
+        #[cfg(target_pointer_width = "64")]
+        assert!(!fits_in_descriptor(u32::MAX as usize + 1));
+
+        let input = [0u8; 16];
+        let mut output = [0u8; 16];
+        assert_eq!(check_buffers(&[&input], &[&mut output]), Ok(()));
+        assert_eq!(check_buffers(&[], &[]), Ok(()));
+    }
+
+    #[test]
+    fn regions_disjoint_detects_overlaps() {
+        assert!(regions_disjoint(&[]));
+        assert!(regions_disjoint(&[(0x1000, 0x100)]));
+        // adjacent regions do not overlap, in any order
+        assert!(regions_disjoint(&[(0x1000, 0x100), (0x1100, 0x100), (0xf00, 0x100)]));
+        assert!(!regions_disjoint(&[(0x1000, 0x100), (0x10ff, 0x100)]));
+        // a region inside another one
+        assert!(!regions_disjoint(&[(0x1000, 0x1000), (0x1800, 0x10)]));
+        // the overlap may be between any two of them
+        assert!(!regions_disjoint(&[(0x1000, 0x10), (0x3000, 0x10), (0x2ff0, 0x20)]));
+    }
+}